@@ -1,12 +1,28 @@
-use eyre::{Context as _, Result};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use eyre::{eyre, Context as _, Result};
 use futures::{SinkExt as _, TryStreamExt as _};
-use lsp_server::Message;
+use lsp_server::{Message, RequestId, Response};
+use lsp_types::{ClientCapabilities, ProgressToken};
 use tokio::{
     spawn,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
     task::JoinHandle,
 };
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
 use tracing::instrument;
 
 use crate::{
@@ -16,9 +32,17 @@ use crate::{
 
 pub struct Client {
     pub sender: UnboundedSender<Message>,
+    shared: Arc<Shared>,
     _handles: [JoinHandle<Result<()>>; 2],
 }
 
+#[derive(Default)]
+struct Shared {
+    pending: Mutex<HashMap<RequestId, oneshot::Sender<Response>>>,
+    capabilities: RwLock<ClientCapabilities>,
+    progresses: Mutex<HashMap<ProgressToken, CancellationToken>>,
+}
+
 impl Client {
     pub fn stdio(event_sender: EventSender) -> Self {
         let (sender, sender_rcv) = unbounded_channel();
@@ -27,9 +51,115 @@ impl Client {
         // TODO: Do something with handles
         Self {
             sender,
+            shared: Default::default(),
             _handles: [handle1, handle2],
         }
     }
+
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            sender: self.sender.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn set_capabilities(&self, capabilities: ClientCapabilities) {
+        *self.shared.capabilities.write().unwrap() = capabilities;
+    }
+
+    /// Passes the response to the request rscls itself sent, and returns whether it was such one.
+    pub fn complete(&self, response: &Response) -> bool {
+        match self.shared.pending.lock().unwrap().remove(&response.id) {
+            Some(sender) => {
+                sender.send(response.clone()).ok();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels the progress rscls itself started, and returns whether it was such one.
+    pub fn cancel_progress(&self, token: &ProgressToken) -> bool {
+        match self.shared.progresses.lock().unwrap().get(token) {
+            Some(cancellation) => {
+                cancellation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sends messages originated from rscls itself, rather than rust-analyzer, to the client.
+#[derive(Clone)]
+pub struct ClientHandle {
+    sender: UnboundedSender<Message>,
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    pub fn capabilities(&self) -> ClientCapabilities {
+        self.shared.capabilities.read().unwrap().clone()
+    }
+
+    pub fn register_progress(&self, token: ProgressToken, cancellation: CancellationToken) {
+        self.shared
+            .progresses
+            .lock()
+            .unwrap()
+            .insert(token, cancellation);
+    }
+
+    pub fn unregister_progress(&self, token: &ProgressToken) {
+        self.shared.progresses.lock().unwrap().remove(token);
+    }
+
+    pub fn notify<N: lsp_types::notification::Notification>(&self, params: N::Params) {
+        let notification = lsp_server::Notification::new(N::METHOD.to_owned(), params);
+        self.sender.send(notification.into()).ok();
+    }
+
+    /// Responds to a request from the client that rscls handled by itself.
+    pub fn respond(&self, id: RequestId, result: Result<serde_json::Value>) {
+        let response = match result {
+            Ok(result) => Response::new_ok(id, result),
+            Err(e) => Response::new_err(
+                id,
+                lsp_server::ErrorCode::InternalError as i32,
+                format!("{e:#}"),
+            ),
+        };
+        self.sender.send(response.into()).ok();
+    }
+
+    /// Sends a request to the client, and returns the future of its response. Note that the
+    /// response is delivered by the main loop, so this must not be awaited there.
+    pub fn request<R: lsp_types::request::Request>(
+        &self,
+        params: R::Params,
+    ) -> impl Future<Output = Result<R::Result>> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        // Use string ids so that they never collide with the ones of rust-analyzer.
+        let id = RequestId::from(format!("rscls-{}", NEXT_ID.fetch_add(1, Ordering::SeqCst)));
+        let (sender, receiver) = oneshot::channel();
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .insert(id.clone(), sender);
+        let request = lsp_server::Request::new(id, R::METHOD.to_owned(), params);
+        // Send it right away so that it precedes whatever is sent after this call.
+        let sent = self.sender.send(request.into());
+        async move {
+            sent.wrap_err("client stopped")?;
+            let response = receiver.await.wrap_err("client stopped")?;
+            if let Some(error) = response.error {
+                return Err(eyre!("`{}` failed: {}", R::METHOD, error.message));
+            }
+            serde_json::from_value(response.result.unwrap_or_default())
+                .wrap_err_with(|| eyre!("invalid response for `{}`", R::METHOD))
+        }
+    }
 }
 
 #[instrument(skip_all)]
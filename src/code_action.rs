@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use lsp_types::{
+    CodeAction, CodeActionKind, Command, Position, Range, TextEdit, Url, WorkspaceEdit,
+};
+use serde_json::{json, Value};
+use toml_edit::{DocumentMut, Item};
+
+use crate::{
+    commands,
+    document::{Document, Encoding},
+    manifest::{self, doc_comment, CargoDeps, Embedded, Manifest},
+};
+
+/// Returns whether the client asked for the action, where `only` of the context lists the kinds
+/// asked or their parents, e.g. `refactor` for `refactor.rewrite`.
+pub fn is_requested(only: Option<&[CodeActionKind]>, action: &CodeAction) -> bool {
+    let Some(only) = only else {
+        return true;
+    };
+    let Some(kind) = &action.kind else {
+        return false;
+    };
+    only.iter().any(|only| {
+        kind.as_str()
+            .strip_prefix(only.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    })
+}
+
+/// Creates actions converting the `cargo-deps` line into an embedded manifest, if `range` touches
+/// the line.
+pub fn convert_cargo_deps_actions(uri: &Url, document: &Document, range: Range) -> Vec<CodeAction> {
+    let Some(cargo_deps) = CargoDeps::find(document) else {
+        return vec![];
+    };
+    if range.start.line > cargo_deps.line || cargo_deps.line > range.end.line {
+        return vec![];
+    }
+    let Some(edits) = convert_cargo_deps(document) else {
+        return vec![];
+    };
+    vec![
+        CodeAction {
+            title: "Convert `cargo-deps` into a manifest block".to_owned(),
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            edit: Some(WorkspaceEdit {
+                changes: Some(HashMap::from([(uri.clone(), edits)])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        CodeAction {
+            title: "Convert `cargo-deps` into a manifest block in all scripts".to_owned(),
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            command: Some(Command::new(
+                "Convert all `cargo-deps`".to_owned(),
+                commands::CONVERT_ALL_CARGO_DEPS.to_owned(),
+                None,
+            )),
+            ..Default::default()
+        },
+    ]
+}
+
+/// Creates an action evaluating the selected expression, if any.
+pub fn evaluate_action(
+    uri: &Url,
+    document: &Document,
+    encoding: Encoding,
+    range: Range,
+) -> Option<CodeAction> {
+    let expression = document.slice(range, encoding).trim();
+    if expression.is_empty() {
+        return None;
+    }
+    Some(CodeAction {
+        title: "Evaluate the selection with rust-script".to_owned(),
+        command: Some(Command::new(
+            "Evaluate".to_owned(),
+            commands::EVALUATE.to_owned(),
+            Some(vec![json!(uri), json!(expression)]),
+        )),
+        ..Default::default()
+    })
+}
+
+/// Creates edits that replace the `cargo-deps` line with an equivalent ```` ```cargo ```` block,
+/// appended to the existing doc comment if any.
+pub fn convert_cargo_deps(document: &Document) -> Option<Vec<TextEdit>> {
+    let cargo_deps = CargoDeps::find(document)?;
+    if Manifest::find(document).is_some() {
+        // rust-script prefers the manifest block anyway.
+        return None;
+    }
+    let line_ending = document.line_ending();
+    let block = Manifest::render(&cargo_deps.to_toml(), line_ending);
+    let line_range = Range::new(
+        Position::new(cargo_deps.line, 0),
+        Position::new(cargo_deps.line + 1, 0),
+    );
+    Some(match doc_comment(document).last() {
+        Some(&(last, _, _)) if last + 1 == cargo_deps.line => {
+            vec![TextEdit::new(
+                line_range,
+                format!("//!{line_ending}{block}"),
+            )]
+        }
+        Some(&(last, _, _)) => {
+            let end = Position::new(last + 1, 0);
+            vec![
+                TextEdit::new(line_range, String::new()),
+                TextEdit::new(Range::new(end, end), format!("//!{line_ending}{block}")),
+            ]
+        }
+        None => vec![TextEdit::new(line_range, block)],
+    })
+}
+
+/// The shebang for scripts run by cargo.
+pub const CARGO_SHEBANG: &str = "#!/usr/bin/env -S cargo +nightly -Zscript";
+
+/// Package fields and targets that cargo rejects or ignores in scripts.
+const UNSUPPORTED_BY_CARGO_SCRIPT: [&str; 9] = [
+    "workspace",
+    "lib",
+    "bin",
+    "example",
+    "test",
+    "bench",
+    "package.build",
+    "package.links",
+    "package.autobins",
+];
+
+/// Creates an action migrating the embedded manifest into a cargo `---` frontmatter, if `range`
+/// touches the shebang or the manifest.
+pub fn migrate_to_frontmatter_actions(
+    uri: &Url,
+    document: &Document,
+    range: Range,
+) -> Vec<CodeAction> {
+    let Some(manifest_lines) = Embedded::find(document).map(|embedded| embedded.lines()) else {
+        return vec![];
+    };
+    let touches =
+        |lines: std::ops::Range<u32>| range.start.line < lines.end && lines.start <= range.end.line;
+    if !touches(0..1) && !touches(manifest_lines) {
+        return vec![];
+    }
+    let Some((edits, warnings)) = migrate_to_frontmatter(document) else {
+        return vec![];
+    };
+    vec![CodeAction {
+        title: "Migrate to cargo script frontmatter".to_owned(),
+        kind: Some(CodeActionKind::REFACTOR_REWRITE),
+        edit: Some(WorkspaceEdit {
+            changes: Some(HashMap::from([(uri.clone(), edits)])),
+            ..Default::default()
+        }),
+        command: (!warnings.is_empty()).then(|| {
+            Command::new(
+                "Show migration warnings".to_owned(),
+                commands::SHOW_WARNINGS.to_owned(),
+                Some(warnings.into_iter().map(Value::from).collect()),
+            )
+        }),
+        ..Default::default()
+    }]
+}
+
+/// Creates edits that move the manifest into a `---cargo` frontmatter and switch the shebang to
+/// cargo, along with warnings for what doesn't translate.
+fn migrate_to_frontmatter(document: &Document) -> Option<(Vec<TextEdit>, Vec<String>)> {
+    let line_ending = document.line_ending();
+    let (toml, removed) = manifest::strip(document)?;
+    let mut toml = toml.parse::<DocumentMut>().ok()?;
+    let mut warnings = vec![];
+    for key in UNSUPPORTED_BY_CARGO_SCRIPT {
+        let mut item = Some(toml.as_item());
+        for key in key.split('.') {
+            item = item.and_then(|item| item.get(key));
+        }
+        if item.is_some() {
+            warnings.push(format!("`{key}` is not supported in cargo scripts."));
+        }
+    }
+    // rust-script defaults to the 2021 edition while cargo warns and uses the latest one.
+    let mut prefix = "";
+    match toml.get_mut("package").and_then(Item::as_table_like_mut) {
+        Some(package) if package.contains_key("edition") => {}
+        Some(package) => {
+            package.insert("edition", toml_edit::value("2021"));
+        }
+        None => prefix = "[package]\nedition = \"2021\"\n\n",
+    }
+    let has_main = (0..document.line_count())
+        .filter_map(|line| document.line(line))
+        .any(|line| line.contains("fn main("));
+    if !has_main {
+        warnings.push("cargo scripts need `fn main`, which rust-script adds by itself.".to_owned());
+    }
+    let uses_env = (0..document.line_count())
+        .filter_map(|line| document.line(line))
+        .any(|line| line.contains("RUST_SCRIPT_"));
+    if uses_env {
+        warnings.push("cargo doesn't set `RUST_SCRIPT_*` environment variables.".to_owned());
+    }
+
+    let mut toml = format!("{prefix}{toml}");
+    if !toml.ends_with('\n') {
+        toml.push('\n');
+    }
+    let frontmatter = format!(
+        "---cargo{line_ending}{}---{line_ending}",
+        toml.lines()
+            .map(|line| format!("{line}{line_ending}"))
+            .collect::<String>()
+    );
+    let has_shebang = document.shebang().is_some();
+    let (header_range, header) = if has_shebang {
+        (
+            Range::new(Position::new(0, 0), Position::new(1, 0)),
+            format!("{CARGO_SHEBANG}{line_ending}{frontmatter}"),
+        )
+    } else {
+        (
+            Range::new(Position::new(0, 0), Position::new(0, 0)),
+            frontmatter,
+        )
+    };
+    let removed_range = Range::new(
+        Position::new(removed.start, 0),
+        Position::new(removed.end, 0),
+    );
+    let edits = if header_range.end == removed_range.start {
+        vec![TextEdit::new(
+            Range::new(header_range.start, removed_range.end),
+            header,
+        )]
+    } else {
+        vec![
+            TextEdit::new(header_range, header),
+            TextEdit::new(removed_range, String::new()),
+        ]
+    };
+    Some((edits, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_requested_by_kind() {
+        let action = CodeAction {
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            ..Default::default()
+        };
+        assert!(is_requested(None, &action));
+        assert!(is_requested(Some(&[CodeActionKind::REFACTOR]), &action));
+        assert!(is_requested(
+            Some(&[CodeActionKind::QUICKFIX, CodeActionKind::REFACTOR_REWRITE]),
+            &action
+        ));
+        assert!(!is_requested(Some(&[CodeActionKind::QUICKFIX]), &action));
+        assert!(!is_requested(Some(&[CodeActionKind::SOURCE]), &action));
+        assert!(!is_requested(Some(&["refactor.re".into()]), &action));
+        assert!(!is_requested(
+            Some(&[CodeActionKind::REFACTOR]),
+            &CodeAction::default()
+        ));
+    }
+}
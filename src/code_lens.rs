@@ -0,0 +1,89 @@
+use lsp_types::{CodeLens, Command, Position, Range, Url};
+use serde_json::json;
+
+use crate::{commands, document::Document, handler};
+
+/// Creates a lens above `fn main`, or at the top of the script if none, to run the script.
+pub fn run_lens(uri: &Url, document: &Document) -> CodeLens {
+    let line = (0..document.line_count())
+        .find(|&line| document.line(line).is_some_and(declares_main))
+        .unwrap_or(0);
+    let position = Position::new(line, 0);
+    CodeLens {
+        range: Range::new(position, position),
+        command: Some(Command::new(
+            "▶ Run script".to_owned(),
+            commands::RUN.to_owned(),
+            Some(vec![json!(uri)]),
+        )),
+        data: Some(handler::own_data()),
+    }
+}
+
+/// Returns whether the line declares `fn main`, possibly with qualifiers such as `pub` or `async`.
+fn declares_main(text: &str) -> bool {
+    let mut rest = text.trim_start();
+    loop {
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(end);
+        let after = after.trim_start();
+        rest = match word {
+            "fn" => {
+                return after
+                    .strip_prefix("main")
+                    .is_some_and(|after| after.trim_start().starts_with(['(', '<']));
+            }
+            // e.g. `pub(crate)` and `extern "C"`.
+            "pub" | "extern" => skip_group(after),
+            "async" | "unsafe" | "const" => after,
+            _ => return false,
+        };
+    }
+}
+
+/// Skips the parenthesized or quoted group at the start of the text, if any.
+fn skip_group(text: &str) -> &str {
+    let close = match text.chars().next() {
+        Some('(') => ')',
+        Some('"') => '"',
+        _ => return text,
+    };
+    text[1..]
+        .split_once(close)
+        .map_or("", |(_, after)| after.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declares_main_with_qualifiers() {
+        for line in [
+            "fn main() {",
+            "  fn main ()",
+            "pub fn main() {",
+            "pub(crate) fn main() {",
+            "async fn main() -> Result<()> {",
+            "pub async unsafe fn main() {",
+            "extern \"C\" fn main() {",
+            "fn\tmain<T>() {",
+        ] {
+            assert!(declares_main(line), "{line}");
+        }
+        for line in [
+            "",
+            "fn main_loop() {",
+            "fn mainly() {",
+            "// fn main() {",
+            "let main = fn main();",
+            "fnmain()",
+            "pub",
+            "pub(crate",
+        ] {
+            assert!(!declares_main(line), "{line}");
+        }
+    }
+}
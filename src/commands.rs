@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, eyre, Result, WrapErr as _};
+use lsp_types::{
+    notification::ShowMessage,
+    request::{ApplyWorkspaceEdit, ExecuteCommand, Request as _, ShowDocument},
+    ApplyWorkspaceEditParams, ExecuteCommandParams, MessageType, ShowDocumentParams,
+    ShowMessageParams, Url, WorkspaceEdit,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    client::ClientHandle,
+    code_action,
+    document::Document,
+    eject,
+    event::{Event, EventSender},
+    generated, new_script, runner, script, settings,
+};
+
+pub const CONVERT_ALL_CARGO_DEPS: &str = "rscls.convertAllCargoDeps";
+pub const SHOW_WARNINGS: &str = "rscls.showWarnings";
+pub const RUN: &str = "rscls.run";
+pub const EVALUATE: &str = "rscls.evaluate";
+pub const BUILD_FOR_DEBUG: &str = "rscls.buildForDebug";
+pub const EJECT: &str = "rscls.eject";
+pub const SHOW_GENERATED: &str = "rscls.showGenerated";
+pub const NEW_SCRIPT: &str = "rscls.newScript";
+pub const MAKE_EXECUTABLE: &str = "rscls.makeExecutable";
+
+/// Commands handled by rscls rather than rust-analyzer.
+pub const COMMANDS: [&str; 9] = [
+    CONVERT_ALL_CARGO_DEPS,
+    SHOW_WARNINGS,
+    RUN,
+    EVALUATE,
+    BUILD_FOR_DEBUG,
+    EJECT,
+    SHOW_GENERATED,
+    NEW_SCRIPT,
+    MAKE_EXECUTABLE,
+];
+
+/// What commands need to know about the state of the main loop.
+pub struct Context {
+    pub client: ClientHandle,
+    pub documents: Vec<(Url, Document)>,
+    pub workspace_roots: Vec<PathBuf>,
+    pub event_sender: EventSender,
+    pub settings: settings::Shared,
+}
+
+/// Extracts the parameters if the request is to execute one of [COMMANDS].
+pub fn own_command(request: &lsp_server::Request) -> Option<ExecuteCommandParams> {
+    if request.method != ExecuteCommand::METHOD {
+        return None;
+    }
+    let params: ExecuteCommandParams = serde_json::from_value(request.params.clone()).ok()?;
+    COMMANDS
+        .contains(&params.command.as_str())
+        .then_some(params)
+}
+
+pub async fn execute(params: ExecuteCommandParams, context: Context) -> Result<Value> {
+    match params.command.as_str() {
+        CONVERT_ALL_CARGO_DEPS => convert_all_cargo_deps(context).await,
+        SHOW_WARNINGS => {
+            for warning in params.arguments {
+                let message = warning.as_str().map_or(warning.to_string(), str::to_owned);
+                context.client.notify::<ShowMessage>(ShowMessageParams {
+                    typ: MessageType::WARNING,
+                    message,
+                });
+            }
+            Ok(Value::Null)
+        }
+        RUN => {
+            let script = script_argument(&params.arguments)?;
+            let args = args_argument(&params.arguments)?;
+            let settings = context.settings.for_path(&script);
+            runner::run_script(&context.client, &settings, &script, &args).await
+        }
+        BUILD_FOR_DEBUG => {
+            let script = script_argument(&params.arguments)?;
+            let args = args_argument(&params.arguments)?;
+            let mode = match params.arguments.get(2) {
+                Some(mode) => mode
+                    .as_str()
+                    .and_then(runner::BuildMode::from_subcommand)
+                    .ok_or_else(|| eyre!("the third argument must be `run`, `test` or `bench`"))?,
+                None => runner::BuildMode::Run,
+            };
+            let settings = context.settings.for_path(&script);
+            runner::build_for_debug(&context.client, &settings, &script, &args, mode).await
+        }
+        EVALUATE => {
+            let script = script_argument(&params.arguments)?;
+            let expression = params
+                .arguments
+                .get(1)
+                .and_then(Value::as_str)
+                .ok_or_else(|| eyre!("the second argument must be the expression to evaluate"))?;
+            let document = script_document(&context.documents, &script).await?;
+            runner::evaluate(
+                &context.client,
+                &context.settings.for_path(&script),
+                &script,
+                &document,
+                expression,
+            )
+            .await
+        }
+        EJECT => eject(&params.arguments, context).await,
+        SHOW_GENERATED => {
+            let script = script_argument(&params.arguments)?;
+            let settings = context.settings.for_path(&script);
+            generated::show(&context.client, &settings, &script).await
+        }
+        NEW_SCRIPT => new_script(&params.arguments, context).await,
+        MAKE_EXECUTABLE => make_executable(&params.arguments, context).await,
+        command => Err(eyre!("unknown command `{command}`")),
+    }
+}
+
+/// Extracts the script path from the first argument, which is the URI of the script.
+fn script_argument(arguments: &[Value]) -> Result<PathBuf> {
+    let uri = arguments
+        .first()
+        .and_then(|uri| serde_json::from_value::<Url>(uri.clone()).ok())
+        .ok_or_else(|| eyre!("the first argument must be the URI of the script"))?;
+    uri.to_file_path()
+        .map_err(|()| eyre!("`{uri}` is not a file"))
+}
+
+/// Returns the opened document of the script, or reads it if not opened.
+async fn script_document(documents: &[(Url, Document)], script: &Path) -> Result<Document> {
+    match documents
+        .iter()
+        .find(|(uri, _)| uri.to_file_path().is_ok_and(|path| path == script))
+    {
+        Some((_, document)) => Ok(document.clone()),
+        None => Ok(Document::new(
+            0,
+            tokio::fs::read_to_string(script)
+                .await
+                .wrap_err_with(|| eyre!("failed to read `{}`", script.display()))?,
+        )),
+    }
+}
+
+/// Extracts the arguments for the script from the optional second argument.
+fn args_argument(arguments: &[Value]) -> Result<Vec<String>> {
+    match arguments.get(1) {
+        Some(args) => {
+            serde_json::from_value(args.clone()).wrap_err("arguments must be an array of strings")
+        }
+        None => Ok(vec![]),
+    }
+}
+
+async fn eject(arguments: &[Value], context: Context) -> Result<Value> {
+    let script = script_argument(arguments)?;
+    let script_dir = script
+        .parent()
+        .ok_or_else(|| eyre!("`{}` has no parent directory", script.display()))?;
+    let dir = match arguments.get(1) {
+        // Relative to the script, not to wherever rscls runs.
+        Some(dir) => script_dir.join(
+            dir.as_str()
+                .ok_or_else(|| eyre!("the second argument must be the directory to create"))?,
+        ),
+        None => eject::default_dir(&script)
+            .ok_or_else(|| eyre!("`{}` has no parent directory", script.display()))?,
+    };
+    let document = script_document(&context.documents, &script).await?;
+    let settings = context.settings.for_path(&script);
+    let ejected = eject::eject(&settings, &script, &document, &dir).await?;
+    let uri = Url::from_file_path(&script).map_err(|()| eyre!("invalid path"))?;
+    context
+        .event_sender
+        .send(Event::Ejected {
+            script: uri,
+            manifest_path: ejected.manifest_path.clone(),
+        })
+        .map_err(|_| eyre!("rscls is stopping"))?;
+    let main_uri = Url::from_file_path(&ejected.main_path).map_err(|()| eyre!("invalid path"))?;
+    let shown = context
+        .client
+        .request::<ShowDocument>(ShowDocumentParams {
+            uri: main_uri,
+            external: None,
+            take_focus: Some(true),
+            selection: None,
+        })
+        .await;
+    if let Err(e) = shown {
+        tracing::warn!(?e, "failed to show the ejected project");
+    }
+    Ok(json!({
+        "manifestPath": ejected.manifest_path,
+        "mainPath": ejected.main_path,
+    }))
+}
+
+/// Creates a script at the path given as the first argument, either a URI or a path relative to
+/// the workspace, with the options `{ "template", "edition" }` given as the second argument.
+async fn new_script(arguments: &[Value], context: Context) -> Result<Value> {
+    let target = arguments
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| eyre!("the first argument must be the path of the new script"))?;
+    let path = match Url::parse(target) {
+        Ok(uri) => uri
+            .to_file_path()
+            .map_err(|()| eyre!("`{uri}` is not a file"))?,
+        Err(_) => match context.workspace_roots.first() {
+            Some(root) => root.join(target),
+            None => PathBuf::from(target),
+        },
+    };
+    let options = arguments.get(1);
+    let option = |key: &str| options.and_then(|options| options.get(key)?.as_str());
+    new_script::create(
+        &context.client,
+        &path,
+        option("template"),
+        option("edition"),
+        context.settings.for_path(&path).template_dir.as_deref(),
+    )
+    .await
+}
+
+/// Does `chmod +x` on the script.
+async fn make_executable(arguments: &[Value], context: Context) -> Result<Value> {
+    let script = script_argument(arguments)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        let mut permissions = tokio::fs::metadata(&script)
+            .await
+            .wrap_err_with(|| eyre!("failed to read `{}`", script.display()))?
+            .permissions();
+        // Let those who can read it execute it.
+        permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+        tokio::fs::set_permissions(&script, permissions)
+            .await
+            .wrap_err_with(|| eyre!("failed to change permissions of `{}`", script.display()))?;
+    }
+    let uri = Url::from_file_path(&script).map_err(|()| eyre!("invalid path"))?;
+    context.event_sender.send(Event::Recheck(uri)).ok();
+    Ok(Value::Null)
+}
+
+async fn convert_all_cargo_deps(context: Context) -> Result<Value> {
+    let mut changes = HashMap::new();
+    for (uri, document) in &context.documents {
+        if let Some(edits) = code_action::convert_cargo_deps(document) {
+            changes.insert(uri.clone(), edits);
+        }
+    }
+    for path in script::discover(&context.workspace_roots, &context.settings.get().patterns).await {
+        let Ok(uri) = Url::from_file_path(&path) else {
+            continue;
+        };
+        if changes.contains_key(&uri) || context.documents.iter().any(|(open, _)| open == &uri) {
+            continue;
+        }
+        let Ok(text) = tokio::fs::read_to_string(&path).await else {
+            continue;
+        };
+        if let Some(edits) = code_action::convert_cargo_deps(&Document::new(0, text)) {
+            changes.insert(uri, edits);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(Value::Null);
+    }
+    let response = context
+        .client
+        .request::<ApplyWorkspaceEdit>(ApplyWorkspaceEditParams {
+            label: Some("Convert `cargo-deps` into manifest blocks".to_owned()),
+            edit: WorkspaceEdit {
+                changes: Some(changes),
+                ..Default::default()
+            },
+        })
+        .await?;
+    if !response.applied {
+        bail!(
+            "the edit was not applied: {}",
+            response.failure_reason.unwrap_or_default()
+        );
+    }
+    Ok(Value::Null)
+}
@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+
+use lsp_types::TextDocumentItem;
+
+use crate::{settings::Settings, shebang};
+
+/// Returns whether the opened document is a script, by its language id, path or shebang, as
+/// editors tend to open scripts as `rust` or `plaintext`.
+pub fn is_script(settings: &Settings, roots: &[PathBuf], document: &TextDocumentItem) -> bool {
+    settings.language_ids.contains(&document.language_id)
+        || document
+            .uri
+            .to_file_path()
+            .is_ok_and(|path| matches_patterns(&settings.patterns, roots, &path))
+        || has_shebang(&document.text)
+}
+
+/// Returns whether the text starts with a shebang running rust-script or cargo.
+pub fn has_shebang(text: &str) -> bool {
+    shebang::first_line(text)
+        .and_then(shebang::interpreter)
+        .is_some()
+}
+
+/// Matches the path against glob patterns, which are matched against file names if they have no
+/// `/`, or paths relative to the workspace folders otherwise.
+pub fn matches_patterns(patterns: &[String], roots: &[PathBuf], path: &Path) -> bool {
+    let name = path.file_name().map(|name| name.to_string_lossy());
+    let relatives = roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .map(|relative| relative.to_string_lossy())
+        .collect::<Vec<_>>();
+    patterns.iter().any(|pattern| {
+        if pattern.contains('/') {
+            let absolute = path.to_string_lossy();
+            let mut candidates = relatives.iter().chain(Some(&absolute));
+            candidates.any(|candidate| glob_match(pattern.as_bytes(), candidate.as_bytes()))
+        } else {
+            name.as_ref()
+                .is_some_and(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+        }
+    })
+}
+
+/// Matches `*` and `?` within a component, and `**` across components.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            // Zero or more whole components.
+            glob_match(rest, text)
+                || (0..text.len())
+                    .filter(|&i| text[i] == b'/')
+                    .any(|i| glob_match(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => {
+                // The rest of the character if it's not ASCII.
+                let len = text.iter().take_while(|&&c| c & 0xC0 == 0x80).count();
+                glob_match(rest, &text[len..])
+            }
+            _ => false,
+        },
+        [p, rest @ ..] => matches!(text, [c, text @ ..] if c == p && glob_match(rest, text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_within_components() {
+        assert!(glob("*.ers", "foo.ers"));
+        assert!(glob("*.ers", ".ers"));
+        assert!(!glob("*.ers", "foo.rs"));
+        assert!(!glob("*.ers", "dir/foo.ers"));
+        assert!(glob("?.ers", "a.ers"));
+        assert!(glob("?.ers", "é.ers"));
+        assert!(!glob("?.ers", "ab.ers"));
+        assert!(!glob("a?b", "a/b"));
+        assert!(glob("scripts/*.rs", "scripts/foo.rs"));
+        assert!(!glob("scripts/*.rs", "scripts/sub/foo.rs"));
+    }
+
+    #[test]
+    fn glob_across_components() {
+        assert!(glob("**", "a/b/c.rs"));
+        assert!(glob("**/*.rs", "foo.rs"));
+        assert!(glob("**/*.rs", "a/b/foo.rs"));
+        assert!(!glob("**/*.rs", "a/b/foo.ers"));
+        assert!(glob("scripts/**/*.rs", "scripts/foo.rs"));
+        assert!(glob("scripts/**/*.rs", "scripts/a/b/foo.rs"));
+        assert!(!glob("scripts/**/*.rs", "other/scripts/foo.rs"));
+        assert!(glob("scripts/**", "scripts/a/foo.rs"));
+    }
+
+    #[test]
+    fn patterns_by_name_or_path() {
+        let roots = [PathBuf::from("/work")];
+        let matches = |pattern: &str, path: &str| {
+            matches_patterns(&[pattern.to_owned()], &roots, Path::new(path))
+        };
+        assert!(matches("*.ers", "/work/a/b.ers"));
+        assert!(matches("*.ers", "/elsewhere/b.ers"));
+        assert!(matches("scripts/*.rs", "/work/scripts/a.rs"));
+        assert!(!matches("scripts/*.rs", "/work/src/scripts/a.rs"));
+        assert!(!matches("scripts/*.rs", "/elsewhere/scripts/a.rs"));
+        assert!(matches("**/scripts/*.rs", "/work/src/scripts/a.rs"));
+        assert!(matches("/opt/**/*.rs", "/opt/a/b.rs"));
+        assert!(!matches_patterns(&[], &roots, Path::new("/work/a.ers")));
+    }
+}
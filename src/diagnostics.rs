@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap};
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, Position, PublishDiagnosticsParams, Range, Url};
+
+use crate::{document::Document, manifest::Embedded};
+
+/// What diagnostics of rscls are about, each of which is replaced separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Shebang,
+    Load,
+}
+
+/// Diagnostics of rscls itself, published along with the ones of rust-analyzer as each publish
+/// replaces all the diagnostics of the document.
+#[derive(Default)]
+pub struct Diagnostics {
+    /// The latest ones of rust-analyzer, only for documents in [Self::own].
+    server: HashMap<Url, PublishDiagnosticsParams>,
+    /// Ours of opened scripts, or of other documents having any.
+    own: HashMap<Url, BTreeMap<Kind, Vec<Diagnostic>>>,
+    /// Ours are kept but not published while disabled.
+    disabled: bool,
+}
+impl Diagnostics {
+    /// Starts keeping the diagnostics of the opened script, to publish them along with ours.
+    pub fn open(&mut self, uri: &Url) {
+        self.own.entry(uri.clone()).or_default();
+    }
+
+    /// Adds ours to the diagnostics rust-analyzer publishes.
+    pub fn merge_server(&mut self, params: &mut PublishDiagnosticsParams) {
+        // Others are never published again by us.
+        let Some(own) = self.own.get(&params.uri) else {
+            return;
+        };
+        self.server.insert(params.uri.clone(), params.clone());
+        if !self.disabled {
+            params.diagnostics.extend(own.values().flatten().cloned());
+        }
+    }
+
+    /// Replaces ours of the kind, and returns the merged diagnostics to publish if changed.
+    pub fn set_own(
+        &mut self,
+        uri: &Url,
+        kind: Kind,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Option<PublishDiagnosticsParams> {
+        let own = self.own.entry(uri.clone()).or_default();
+        let previous = own.get(&kind).map_or(&[][..], Vec::as_slice);
+        if previous == diagnostics.as_slice() {
+            return None;
+        }
+        if diagnostics.is_empty() {
+            own.remove(&kind);
+        } else {
+            own.insert(kind, diagnostics);
+        }
+        (!self.disabled).then(|| self.merged(uri))
+    }
+
+    /// Enables or disables ours, and returns the diagnostics to publish again if it changed.
+    pub fn set_enabled(&mut self, enabled: bool) -> Vec<PublishDiagnosticsParams> {
+        if self.disabled != enabled {
+            return vec![];
+        }
+        self.disabled = !enabled;
+        self.own
+            .iter()
+            .filter(|(_, own)| !own.is_empty())
+            .map(|(uri, _)| self.merged(uri))
+            .collect()
+    }
+
+    fn merged(&self, uri: &Url) -> PublishDiagnosticsParams {
+        let mut params = self
+            .server
+            .get(uri)
+            .cloned()
+            .unwrap_or_else(|| PublishDiagnosticsParams::new(uri.clone(), vec![], None));
+        if let (false, Some(own)) = (self.disabled, self.own.get(uri)) {
+            params.diagnostics.extend(own.values().flatten().cloned());
+        }
+        params
+    }
+
+    /// Forgets the document, and returns an empty publish to clear ours if any.
+    pub fn close(&mut self, uri: &Url) -> Option<PublishDiagnosticsParams> {
+        self.server.remove(uri);
+        self.own
+            .remove(uri)
+            .filter(|own| !own.is_empty())
+            .map(|_| PublishDiagnosticsParams::new(uri.clone(), vec![], None))
+    }
+}
+
+/// Creates a diagnostic on the manifest, or the top of the script if none, telling rust-script
+/// failed to load the script.
+pub fn load_failure(document: Option<&Document>, error: String) -> Diagnostic {
+    let lines = document
+        .and_then(Embedded::find)
+        .map_or(0..1, |embedded| embedded.lines());
+    let range = Range::new(Position::new(lines.start, 0), Position::new(lines.end, 0));
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("rscls".to_owned()),
+        message: format!("Failed to load the script, analyzing it without dependencies: {error}"),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,122 @@
+use eyre::{bail, eyre, Result, WrapErr as _};
+use serde_json::{json, Map, Value};
+use toml_edit::{DocumentMut, Item};
+
+use crate::shebang;
+
+const PREFIX: &str = "rscls:";
+
+/// Hints for analyzing a script, given by `// rscls: key = value` lines among its leading
+/// comments, e.g. `// rscls: features = ["full"]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// The target triple to analyze the script for.
+    pub target: Option<String>,
+    /// Features of the package of the script to enable.
+    pub features: Vec<String>,
+    /// Extra `cfg`s as in `rust-project.json`, e.g. `unix` or `feature="x"`.
+    pub cfg: Vec<String>,
+}
+
+impl Directives {
+    /// Parses the directives among the leading comments of the script, ignoring invalid ones.
+    pub fn parse(text: &str) -> Self {
+        let mut directives = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if i == 0 && shebang::first_line(line).is_some() {
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let Some(comment) = line.strip_prefix("//") else {
+                break;
+            };
+            let Some(directive) = comment.trim_start().strip_prefix(PREFIX) else {
+                continue;
+            };
+            if let Err(e) = directives.apply(directive.trim()) {
+                tracing::warn!(?e, directive, "ignoring invalid directive");
+            }
+        }
+        directives
+    }
+
+    fn apply(&mut self, directive: &str) -> Result<()> {
+        let toml = directive
+            .parse::<DocumentMut>()
+            .wrap_err("directives should be `key = value`")?;
+        for (key, item) in toml.iter() {
+            match key {
+                "target" => {
+                    let target = item
+                        .as_str()
+                        .ok_or_else(|| eyre!("`target` should be a string"))?;
+                    self.target = Some(target.to_owned());
+                }
+                "features" => self.features = strings(key, item)?,
+                "cfg" => self.cfg = strings(key, item)?,
+                _ => bail!("unknown directive `{key}`"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the settings of rust-analyzer applying the directives to the generated package.
+    pub fn rust_analyzer_settings(&self) -> Value {
+        let mut cargo = Map::new();
+        if let Some(target) = &self.target {
+            cargo.insert("target".to_owned(), json!(target));
+        }
+        if !self.features.is_empty() {
+            cargo.insert("features".to_owned(), json!(self.features));
+        }
+        if !self.cfg.is_empty() {
+            // These replace the defaults of rust-analyzer, and take values without quotes.
+            let cfgs = ["debug_assertions", "miri"]
+                .into_iter()
+                .map(str::to_owned)
+                .chain(self.cfg.iter().map(|cfg| match cfg.split_once('=') {
+                    Some((key, value)) => {
+                        format!("{}={}", key.trim(), value.trim().trim_matches('"'))
+                    }
+                    None => cfg.trim().to_owned(),
+                }))
+                .collect::<Vec<_>>();
+            cargo.insert("cfgs".to_owned(), json!(cfgs));
+        }
+        if cargo.is_empty() {
+            Value::Null
+        } else {
+            json!({ "cargo": cargo })
+        }
+    }
+
+    /// Applies the directives to a crate of `rust-project.json`, whose features are `cfg`s.
+    pub fn apply_to_crate(&self, krate: &mut Map<String, Value>) {
+        if let Some(target) = &self.target {
+            krate.insert("target".to_owned(), json!(target));
+        }
+        let cfg = self
+            .features
+            .iter()
+            .map(|feature| format!("feature=\"{feature}\""))
+            .chain(self.cfg.iter().cloned())
+            .collect::<Vec<_>>();
+        if !cfg.is_empty() {
+            krate.insert("cfg".to_owned(), json!(cfg));
+        }
+    }
+}
+
+fn strings(key: &str, item: &Item) -> Result<Vec<String>> {
+    item.as_array()
+        .and_then(|array| {
+            array
+                .iter()
+                .map(|value| value.as_str().map(str::to_owned))
+                .collect()
+        })
+        .ok_or_else(|| eyre!("`{key}` should be an array of strings"))
+}
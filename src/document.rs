@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use lsp_types::{Position, PositionEncodingKind, Range, TextDocumentContentChangeEvent, Url};
+
+use crate::shebang;
+
+/// How `character` of a [Position] counts, negotiated between the client and rust-analyzer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    Utf8,
+    #[default]
+    Utf16,
+    Utf32,
+}
+impl Encoding {
+    pub fn from_kind(kind: &PositionEncodingKind) -> Self {
+        if kind == &PositionEncodingKind::UTF8 {
+            Self::Utf8
+        } else if kind == &PositionEncodingKind::UTF32 {
+            Self::Utf32
+        } else {
+            Self::Utf16
+        }
+    }
+
+    fn len(self, c: char) -> usize {
+        match self {
+            Self::Utf8 => c.len_utf8(),
+            Self::Utf16 => c.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+
+    /// Returns the length of the `str` in this encoding.
+    pub fn str_len(self, s: &str) -> u32 {
+        s.chars().map(|c| self.len(c)).sum::<usize>() as u32
+    }
+
+    /// Converts an offset in this encoding within `line` into a byte offset.
+    pub fn byte_offset(self, line: &str, character: u32) -> usize {
+        let mut units = 0;
+        for (i, c) in line.char_indices() {
+            if units >= character as usize {
+                return i;
+            }
+            units += self.len(c);
+        }
+        line.len()
+    }
+}
+
+/// An opened text document, kept in sync with the client.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub version: i32,
+    text: String,
+    line_starts: Vec<usize>,
+}
+impl Document {
+    pub fn new(version: i32, text: String) -> Self {
+        let line_starts = line_starts(&text);
+        Self {
+            version,
+            text,
+            line_starts,
+        }
+    }
+
+    /// Returns the content of the `line`-th line without the line terminator.
+    pub fn line(&self, line: u32) -> Option<&str> {
+        let start = *self.line_starts.get(line as usize)?;
+        let end = self
+            .line_starts
+            .get(line as usize + 1)
+            .copied()
+            .unwrap_or(self.text.len());
+        let line = &self.text[start..end];
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        Some(line.strip_suffix('\r').unwrap_or(line))
+    }
+
+    /// Returns the first line if it's a shebang.
+    pub fn shebang(&self) -> Option<&str> {
+        self.line(0).and_then(shebang::first_line)
+    }
+
+    /// Returns the line terminator the document uses.
+    pub fn line_ending(&self) -> &'static str {
+        if self.text.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+
+    pub fn line_count(&self) -> u32 {
+        self.line_starts.len() as u32
+    }
+
+    pub fn offset(&self, position: Position, encoding: Encoding) -> usize {
+        match self.line(position.line) {
+            Some(line) => {
+                self.line_starts[position.line as usize]
+                    + encoding.byte_offset(line, position.character)
+            }
+            None => self.text.len(),
+        }
+    }
+
+    /// Returns the text within the `range`.
+    pub fn slice(&self, range: Range, encoding: Encoding) -> &str {
+        let start = self.offset(range.start, encoding);
+        let end = self.offset(range.end, encoding).max(start);
+        &self.text[start..end]
+    }
+
+    pub fn apply_change(&mut self, change: TextDocumentContentChangeEvent, encoding: Encoding) {
+        match change.range {
+            Some(Range { start, end }) => {
+                let start = self.offset(start, encoding);
+                let end = self.offset(end, encoding).max(start);
+                self.text.replace_range(start..end, &change.text);
+            }
+            None => self.text = change.text,
+        }
+        self.line_starts = line_starts(&self.text);
+    }
+}
+
+fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// Opened script documents.
+#[derive(Default)]
+pub struct Documents {
+    encoding: Encoding,
+    documents: HashMap<Url, Document>,
+}
+impl Documents {
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Url, &Document)> {
+        self.documents.iter()
+    }
+
+    pub fn open(&mut self, uri: Url, version: i32, text: String) {
+        self.documents.insert(uri, Document::new(version, text));
+    }
+
+    pub fn change(
+        &mut self,
+        uri: &Url,
+        version: i32,
+        changes: impl IntoIterator<Item = TextDocumentContentChangeEvent>,
+    ) {
+        if let Some(document) = self.documents.get_mut(uri) {
+            for change in changes {
+                document.apply_change(change, self.encoding);
+            }
+            document.version = version;
+        }
+    }
+
+    pub fn close(&mut self, uri: &Url) {
+        self.documents.remove(uri);
+    }
+}
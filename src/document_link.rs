@@ -0,0 +1,148 @@
+use std::path::{Path, PathBuf};
+
+use lsp_types::{DocumentLink, Url};
+use path_absolutize::Absolutize as _;
+
+use crate::{
+    document::{Document, Encoding},
+    lockfile::{LockedPackage, Lockfile},
+    manifest::{Dependency, Manifest},
+};
+
+/// Creates links from dependencies in the embedded manifest.
+///
+/// The name of a dependency links to its `Cargo.toml` for `path` dependencies, its checkout for
+/// `git` dependencies and its docs.rs page otherwise. The version of a registry dependency links to
+/// its extracted source.
+pub async fn manifest_links(
+    document: &Document,
+    encoding: Encoding,
+    script: &Path,
+    lockfile: Option<&Lockfile>,
+) -> Vec<DocumentLink> {
+    let Some(manifest) = Manifest::find(document) else {
+        return vec![];
+    };
+    let script_dir = script.parent().unwrap_or(script);
+    let dependencies = manifest.dependencies();
+    let packages = dependencies
+        .iter()
+        .map(|dependency| {
+            lockfile
+                .and_then(|lockfile| lockfile.resolve(&dependency.package))
+                .cloned()
+        })
+        .collect::<Vec<_>>();
+    let locations = locate(&dependencies, &packages).await;
+    let mut links = vec![];
+    for ((dependency, package), location) in dependencies.into_iter().zip(packages).zip(locations) {
+        let mut link = |span, target: Option<Url>, tooltip: &str| {
+            if let (Some(range), Some(target)) =
+                (manifest.script_range(document, encoding, span), target)
+            {
+                links.push(DocumentLink {
+                    range,
+                    target: Some(target),
+                    tooltip: Some(tooltip.to_owned()),
+                    data: None,
+                });
+            }
+        };
+        if let Some(path) = &dependency.path {
+            let target = script_dir
+                .join(path)
+                .join("Cargo.toml")
+                .absolutize()
+                .ok()
+                .and_then(|manifest| Url::from_file_path(manifest).ok());
+            link(dependency.key_span, target, "Open Cargo.toml");
+        } else if dependency.git.is_some() {
+            let target = location.and_then(|checkout| Url::from_directory_path(checkout).ok());
+            link(dependency.key_span, target, "Open checkout");
+        } else {
+            link(
+                dependency.key_span.clone(),
+                docs_rs(&dependency, package.as_ref()),
+                "Open docs.rs",
+            );
+            let target =
+                location.and_then(|source| Url::from_file_path(source.join("Cargo.toml")).ok());
+            link(dependency.value_span, target, "Open source");
+        }
+    }
+    links
+}
+
+/// Finds the checkouts of `git` dependencies and the sources of registry ones, off the main loop
+/// as it scans directories in the cargo home.
+async fn locate(
+    dependencies: &[Dependency],
+    packages: &[Option<LockedPackage>],
+) -> Vec<Option<PathBuf>> {
+    let queries = dependencies
+        .iter()
+        .zip(packages)
+        .map(|(dependency, package)| {
+            Some((dependency.git.is_some(), package.clone()?)).filter(|_| dependency.path.is_none())
+        })
+        .collect::<Vec<_>>();
+    let count = queries.len();
+    tokio::task::spawn_blocking(move || {
+        queries
+            .into_iter()
+            .map(|query| match query? {
+                (true, package) => git_checkout(&package),
+                (false, package) => registry_source(&package),
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_else(|e| {
+        tracing::warn!(?e, "failed to locate dependencies");
+        vec![None; count]
+    })
+}
+
+fn docs_rs(dependency: &Dependency, package: Option<&LockedPackage>) -> Option<Url> {
+    let version = package.map_or("latest", |package| package.version.as_str());
+    Url::parse(&format!("https://docs.rs/{}/{version}", dependency.package)).ok()
+}
+
+fn cargo_home() -> Option<PathBuf> {
+    std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))
+}
+
+/// Finds the extracted source of the package, i.e. `$CARGO_HOME/registry/src/*/<name>-<version>`.
+fn registry_source(package: &LockedPackage) -> Option<PathBuf> {
+    let name = format!("{}-{}", package.name, package.version);
+    std::fs::read_dir(cargo_home()?.join("registry").join("src"))
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|index| index.path().join(&name))
+        .find(|source| source.is_dir())
+}
+
+/// Finds the checkout of the package, i.e. `$CARGO_HOME/git/checkouts/<repository>-<hash>/<rev>`.
+fn git_checkout(package: &LockedPackage) -> Option<PathBuf> {
+    // e.g. `git+https://github.com/owner/repository?branch=main#0123456789abcdef`
+    let source = package.source.as_ref()?.strip_prefix("git+")?;
+    let (url, rev) = source.split_once('#')?;
+    let url = url.split('?').next()?;
+    let repository = url.trim_end_matches('/').rsplit('/').next()?;
+    let repository = repository.strip_suffix(".git").unwrap_or(repository);
+    let short_rev = rev.get(..7)?;
+    std::fs::read_dir(cargo_home()?.join("git").join("checkouts"))
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|checkout| {
+            checkout
+                .file_name()
+                .to_string_lossy()
+                .strip_prefix(repository)
+                .is_some_and(|hash| hash.starts_with('-'))
+        })
+        .map(|checkout| checkout.path().join(short_rev))
+        .find(|checkout| checkout.is_dir())
+}
@@ -0,0 +1,255 @@
+use std::path::{Component, Path, PathBuf};
+
+use eyre::{ensure, eyre, Result, WrapErr as _};
+use path_absolutize::Absolutize as _;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::{document::Document, manifest, script, settings::Settings};
+
+/// Where a script was ejected into.
+pub struct Ejected {
+    pub manifest_path: PathBuf,
+    pub main_path: PathBuf,
+}
+
+/// Creates a Cargo project in `dir` from the script, i.e. `Cargo.toml` from the embedded manifest,
+/// `src/main.rs` from the rest of the script and `Cargo.lock` of the package generated for it.
+pub async fn eject(
+    settings: &Settings,
+    script: &Path,
+    document: &Document,
+    dir: &Path,
+) -> Result<Ejected> {
+    ensure!(
+        tokio::fs::metadata(dir).await.is_err(),
+        "`{}` already exists",
+        dir.display()
+    );
+    let name = package_name(script);
+    let script_dir = script
+        .parent()
+        .ok_or_else(|| eyre!("`{}` has no parent directory", script.display()))?;
+    let (cargo_toml, main_rs) = split(document, &name, script_dir, dir)?;
+    let manifest_path = dir.join("Cargo.toml");
+    let main_path = dir.join("src").join("main.rs");
+    tokio::fs::create_dir_all(dir.join("src"))
+        .await
+        .wrap_err_with(|| eyre!("failed to create `{}`", dir.display()))?;
+    tokio::fs::write(&manifest_path, cargo_toml)
+        .await
+        .wrap_err_with(|| eyre!("failed to write `{}`", manifest_path.display()))?;
+    tokio::fs::write(&main_path, main_rs)
+        .await
+        .wrap_err_with(|| eyre!("failed to write `{}`", main_path.display()))?;
+    // Keep the resolved versions. The root package in it is renamed, which cargo fixes by itself.
+    match script::package_dir(settings, script).await {
+        Ok(package_dir) => {
+            let lockfile = package_dir.join("Cargo.lock");
+            if tokio::fs::metadata(&lockfile).await.is_ok() {
+                tokio::fs::copy(&lockfile, dir.join("Cargo.lock"))
+                    .await
+                    .wrap_err_with(|| eyre!("failed to copy `{}`", lockfile.display()))?;
+            }
+        }
+        Err(e) => tracing::warn!(?e, "not copying Cargo.lock of the generated package"),
+    }
+    Ok(Ejected {
+        manifest_path,
+        main_path,
+    })
+}
+
+/// Returns the directory to eject the script into by default, i.e. next to the script.
+pub fn default_dir(script: &Path) -> Option<PathBuf> {
+    Some(script.parent()?.join(package_name(script)))
+}
+
+/// Names the package after the script as rust-script does.
+fn package_name(script: &Path) -> String {
+    let stem = script
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() {
+        "script".to_owned()
+    } else {
+        name
+    }
+}
+
+/// Splits the script into `Cargo.toml` and `src/main.rs`, where `Cargo.toml` is to be written in
+/// `dir` while the manifest was relative to `script_dir`.
+fn split(
+    document: &Document,
+    name: &str,
+    script_dir: &Path,
+    dir: &Path,
+) -> Result<(String, String)> {
+    let (toml, removed) = manifest::strip(document).unwrap_or_default();
+    let mut toml = toml
+        .parse::<DocumentMut>()
+        .wrap_err("the embedded manifest is invalid")?;
+    rebase_paths(toml.as_table_mut(), script_dir, dir);
+    let mut prefix = String::new();
+    let defaults = [("name", name), ("version", "0.1.0"), ("edition", "2021")];
+    match toml.get_mut("package").and_then(Item::as_table_like_mut) {
+        Some(package) => {
+            for (key, value) in defaults {
+                if !package.contains_key(key) {
+                    package.insert(key, toml_edit::value(value));
+                }
+            }
+        }
+        None => {
+            prefix.push_str("[package]\n");
+            for (key, value) in defaults {
+                prefix.push_str(&format!("{key} = \"{value}\"\n"));
+            }
+            prefix.push('\n');
+        }
+    }
+    let cargo_toml = format!("{prefix}{toml}");
+
+    let line_ending = document.line_ending();
+    let has_shebang = document.shebang().is_some();
+    let mut lines = (0..document.line_count())
+        .skip(usize::from(has_shebang))
+        .filter(|line| !removed.contains(line))
+        .filter_map(|line| document.line(line))
+        .collect::<Vec<_>>();
+    // The empty line after the last line ending, if any.
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    let main_rs = lines
+        .into_iter()
+        .map(|line| format!("{line}{line_ending}"))
+        .collect::<String>();
+    Ok((cargo_toml, main_rs))
+}
+
+/// Rewrites relative `path`s of dependencies, including the ones for targets and patches, to
+/// point to the same directories from `dir` as from `script_dir`.
+pub fn rebase_paths(toml: &mut dyn TableLike, script_dir: &Path, dir: &Path) {
+    let mut tables = vec![];
+    for (key, item) in toml.iter_mut() {
+        let Some(table) = item.as_table_like_mut() else {
+            continue;
+        };
+        match key.get() {
+            key if manifest::DEPENDENCY_TABLES.contains(&key) => tables.push(table),
+            // `[target.'cfg(unix)'.dependencies]` and so on.
+            "target" => {
+                for (_, target) in table.iter_mut() {
+                    let Some(target) = target.as_table_like_mut() else {
+                        continue;
+                    };
+                    tables.extend(
+                        target
+                            .iter_mut()
+                            .filter(|(key, _)| manifest::DEPENDENCY_TABLES.contains(&key.get()))
+                            .filter_map(|(_, item)| item.as_table_like_mut()),
+                    );
+                }
+            }
+            // `[patch.crates-io]` and so on, which list dependencies by themselves.
+            "patch" => tables.extend(
+                table
+                    .iter_mut()
+                    .filter_map(|(_, item)| item.as_table_like_mut()),
+            ),
+            _ => {}
+        }
+    }
+    for table in tables {
+        for (_, dependency) in table.iter_mut() {
+            let Some(path) = dependency
+                .as_table_like_mut()
+                .and_then(|dependency| dependency.get_mut("path"))
+            else {
+                continue;
+            };
+            let Some(relative) = path
+                .as_str()
+                .map(Path::new)
+                .filter(|path| path.is_relative())
+            else {
+                continue;
+            };
+            let rebased = rebase(&script_dir.join(relative), dir);
+            if let Some(value) = path.as_value_mut() {
+                let decor = value.decor().clone();
+                *value = rebased.to_string_lossy().as_ref().into();
+                *value.decor_mut() = decor;
+            }
+        }
+    }
+}
+
+/// Returns `path` relative to `dir` if they share a root, or absolute otherwise.
+fn rebase(path: &Path, dir: &Path) -> PathBuf {
+    let (Ok(path), Ok(dir)) = (path.absolutize(), dir.absolutize()) else {
+        return path.to_path_buf();
+    };
+    let mut path_components = path.components().peekable();
+    let mut dir_components = dir.components().peekable();
+    if path_components.peek() != dir_components.peek() {
+        return path.to_path_buf();
+    }
+    while let (Some(a), Some(b)) = (path_components.peek(), dir_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        dir_components.next();
+    }
+    let mut rebased = dir_components
+        .map(|_| Component::ParentDir)
+        .collect::<PathBuf>();
+    rebased.extend(path_components);
+    if rebased.as_os_str().is_empty() {
+        rebased.push(".");
+    }
+    rebased
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_rebases_paths() {
+        let script = concat!(
+            "#!/usr/bin/env rust-script\n",
+            "//! ```cargo\n",
+            "//! [dependencies]\n",
+            "//! foo = { path = \"../foo\" }\n",
+            "//! bar = { path = \"/opt/bar\" }\n",
+            "//! [target.'cfg(unix)'.dev-dependencies]\n",
+            "//! baz = { path = \"baz\", version = \"1\" }\n",
+            "//! ```\n",
+            "fn main() {}",
+        );
+        let document = Document::new(0, script.to_owned());
+        let (cargo_toml, main_rs) = split(
+            &document,
+            "script",
+            Path::new("/work/scripts"),
+            Path::new("/work/scripts/script"),
+        )
+        .unwrap();
+        assert!(cargo_toml.contains(r#"foo = { path = "../../foo" }"#));
+        assert!(cargo_toml.contains(r#"bar = { path = "/opt/bar" }"#));
+        assert!(cargo_toml.contains(r#"baz = { path = "../baz", version = "1" }"#));
+        assert_eq!(main_rs, "fn main() {}\n");
+
+        let document = Document::new(0, "fn main() {}\r\n".to_owned());
+        let (_, main_rs) =
+            split(&document, "script", Path::new("/"), Path::new("/script")).unwrap();
+        assert_eq!(main_rs, "fn main() {}\r\n");
+    }
+}
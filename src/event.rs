@@ -1,11 +1,16 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use lsp_server::Message;
 use tokio::sync::mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender};
 
+use crate::shebang;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Version(usize);
 
@@ -15,6 +20,23 @@ pub enum Event {
     ServerToClient(Message),
     ServerLog(String),
     NeedReload(Version),
+    /// rust-script loaded the script, or failed to with the error.
+    Loaded {
+        script: lsp_types::Url,
+        error: Option<String>,
+    },
+    /// The file of the script changed outside of the client, e.g. its permission.
+    Recheck(lsp_types::Url),
+    /// The interpreter of the shebang of the script was looked up.
+    ShebangLookedUp {
+        script: lsp_types::Url,
+        lookup: shebang::Lookup,
+    },
+    /// The script was ejected into the Cargo project.
+    Ejected {
+        script: lsp_types::Url,
+        manifest_path: PathBuf,
+    },
 }
 
 pub type EventReceiver = UnboundedReceiver<Event>;
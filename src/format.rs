@@ -0,0 +1,122 @@
+use lsp_types::{Position, Range, TextEdit};
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::{
+    document::Document,
+    manifest::{Manifest, DEPENDENCY_TABLES},
+};
+
+/// Computes an edit that formats the embedded manifest of the script, if it needs any.
+pub fn manifest_edit(document: &Document) -> Option<TextEdit> {
+    let manifest = Manifest::find(document)?;
+    let mut toml = match manifest.toml().parse::<DocumentMut>() {
+        Ok(toml) => toml,
+        Err(e) => {
+            tracing::info!(?e, "not formatting an invalid manifest");
+            return None;
+        }
+    };
+    normalize(toml.as_table_mut());
+    let line_ending = document.line_ending();
+    let new_text = Manifest::render(&toml.to_string(), line_ending);
+    let range = Range::new(
+        Position::new(manifest.start_line, 0),
+        Position::new(manifest.end_line + 1, 0),
+    );
+    let old_text = (manifest.start_line..=manifest.end_line)
+        .filter_map(|line| document.line(line))
+        .map(|line| format!("{line}{line_ending}"))
+        .collect::<String>();
+    (old_text != new_text).then_some(TextEdit::new(range, new_text))
+}
+
+/// Adds `edit` to `edits` unless it overlaps with any of them, in which case the rustfmt's ones win.
+pub fn merge_edits(edits: &mut Vec<TextEdit>, edit: TextEdit) {
+    let overlaps = |other: &TextEdit| {
+        other.range.start < edit.range.end && edit.range.start < other.range.end
+            || other.range.start == edit.range.start
+    };
+    if edits.iter().any(overlaps) {
+        tracing::info!("manifest formatting conflicts with rustfmt, skipping");
+        return;
+    }
+    edits.push(edit);
+}
+
+fn normalize(root: &mut Table) {
+    for (key, item) in root.iter_mut() {
+        let Some(table) = item.as_table_like_mut() else {
+            continue;
+        };
+        if DEPENDENCY_TABLES.contains(&key.get()) {
+            table.sort_values();
+            for (_, item) in table.iter_mut() {
+                normalize_inline(item);
+            }
+        } else if key.get() == "target" {
+            for (_, platform) in table.iter_mut() {
+                if let Some(platform) = platform.as_table_mut() {
+                    normalize(platform);
+                }
+            }
+        } else {
+            for (_, item) in table.iter_mut() {
+                normalize_inline(item);
+            }
+        }
+    }
+}
+
+fn normalize_inline(item: &mut Item) {
+    match item {
+        Item::Value(Value::InlineTable(table)) => {
+            table.fmt();
+            for (_, value) in table.iter_mut() {
+                if let Value::Array(array) = value {
+                    array.fmt();
+                }
+            }
+        }
+        Item::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                normalize_inline(item);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(start: (u32, u32), end: (u32, u32)) -> TextEdit {
+        TextEdit::new(
+            Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            String::new(),
+        )
+    }
+
+    #[test]
+    fn merge_edits_unless_overlapping() {
+        let mut edits = vec![edit((5, 0), (6, 4))];
+        merge_edits(&mut edits, edit((1, 0), (5, 0)));
+        assert_eq!(edits.len(), 2, "touching edits are both kept");
+
+        let mut edits = vec![edit((5, 0), (6, 4))];
+        merge_edits(&mut edits, edit((1, 0), (5, 1)));
+        assert_eq!(edits, [edit((5, 0), (6, 4))]);
+
+        let mut edits = vec![edit((3, 0), (3, 0))];
+        merge_edits(&mut edits, edit((3, 0), (4, 0)));
+        assert_eq!(
+            edits,
+            [edit((3, 0), (3, 0))],
+            "insertions at the start conflict"
+        );
+
+        let mut edits = vec![];
+        merge_edits(&mut edits, edit((1, 0), (4, 0)));
+        assert_eq!(edits, [edit((1, 0), (4, 0))]);
+    }
+}
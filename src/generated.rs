@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{ensure, eyre, Result, WrapErr as _};
+use lsp_types::{
+    request::{Request as _, ShowDocument},
+    ShowDocumentParams, Url,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    client::ClientHandle,
+    lsp_extra::{GeneratedFile, GeneratedFileParams, GeneratedFileResult},
+    script,
+    settings::Settings,
+};
+
+/// The URI scheme of the files in packages generated by rust-script, served by
+/// [GeneratedFile] requests.
+pub const SCHEME: &str = "rscls-generated";
+
+/// Shows the files of the package generated for the script, and returns their URIs.
+pub async fn show(client: &ClientHandle, settings: &Settings, script: &Path) -> Result<Value> {
+    let package_dir = script::package_dir(settings, script).await?;
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(&package_dir)
+        .await
+        .wrap_err_with(|| eyre!("failed to read `{}`", package_dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    // Cargo.toml first as it's the most interesting one.
+    files.sort_by_key(|path| (!path.ends_with("Cargo.toml"), path.clone()));
+    let uris = files
+        .iter()
+        .filter_map(|path| uri(path))
+        .collect::<Vec<_>>();
+    if let Some(uri) = uris.first() {
+        let shown = client
+            .request::<ShowDocument>(ShowDocumentParams {
+                uri: uri.clone(),
+                external: None,
+                take_focus: Some(true),
+                selection: None,
+            })
+            .await;
+        if let Err(e) = shown {
+            tracing::warn!(?e, "failed to show the generated package");
+        }
+    }
+    Ok(json!({
+        "files": uris,
+        "rustScriptVersion": version(settings).await.ok(),
+    }))
+}
+
+/// Extracts the parameters if the request is [GeneratedFile].
+pub fn own_request(request: &lsp_server::Request) -> Option<GeneratedFileParams> {
+    if request.method != GeneratedFile::METHOD {
+        return None;
+    }
+    serde_json::from_value(request.params.clone()).ok()
+}
+
+/// Reads the generated file, which must be directly under one of the `package_dirs`.
+pub async fn read(
+    params: GeneratedFileParams,
+    package_dirs: Vec<PathBuf>,
+    settings: &Settings,
+) -> Result<Value> {
+    let path =
+        path(&params.uri).ok_or_else(|| eyre!("`{}` is not a generated file", params.uri))?;
+    ensure!(
+        path.parent()
+            .is_some_and(|parent| package_dirs.iter().any(|dir| dir == parent)),
+        "`{}` is not in a package generated for an opened script",
+        path.display()
+    );
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .wrap_err_with(|| eyre!("failed to read `{}`", path.display()))?;
+    Ok(serde_json::to_value(GeneratedFileResult {
+        text,
+        rust_script_version: version(settings).await.ok(),
+    })?)
+}
+
+fn uri(path: &Path) -> Option<Url> {
+    let file = Url::from_file_path(path).ok()?;
+    Url::parse(&format!("{SCHEME}:{}", file.path())).ok()
+}
+
+fn path(uri: &Url) -> Option<PathBuf> {
+    if uri.scheme() != SCHEME {
+        return None;
+    }
+    Url::parse(&format!("file://{}", uri.path()))
+        .ok()?
+        .to_file_path()
+        .ok()
+}
+
+/// Returns the version of rust-script, e.g. `0.35.0`.
+async fn version(settings: &Settings) -> Result<String> {
+    let output = settings
+        .rust_script_command()
+        .arg("--version")
+        .output()
+        .await
+        .wrap_err("failed to run rust-script")?;
+    ensure!(output.status.success(), "`rust-script --version` failed");
+    let output = String::from_utf8_lossy(&output.stdout);
+    let output = output.trim();
+    Ok(output
+        .strip_prefix("rust-script ")
+        .unwrap_or(output)
+        .to_owned())
+}
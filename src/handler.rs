@@ -1,5 +1,8 @@
 use std::future::Future;
 
+use lsp_types::request::{CodeActionResolveRequest, CodeLensResolve, Request as _};
+use serde_json::{json, Value};
+
 /// This type exists to wrap arguments for closures so that
 /// the they will be moved even if they were [Copy].
 /// See: [this article](https://zenn.dev/luma/articles/rust-why-and-how-force-move-copy-trait).
@@ -10,6 +13,28 @@ impl<T> Move<T> {
     }
 }
 
+/// The key in `data` of code actions and code lenses of ours.
+const OWN_DATA_KEY: &str = "rscls";
+
+/// Returns `data` for code actions and code lenses of ours, telling them from ones of
+/// rust-analyzer when they're resolved.
+pub fn own_data() -> Value {
+    json!({ OWN_DATA_KEY: true })
+}
+
+/// Returns the item as is if it's a request to resolve a code action or a code lens of ours, which
+/// rust-analyzer doesn't know how to resolve.
+pub fn resolve_own(request: &lsp_server::Request) -> Option<Value> {
+    let is_resolve = request.method == CodeActionResolveRequest::METHOD
+        || request.method == CodeLensResolve::METHOD;
+    let is_own = request
+        .params
+        .get("data")
+        .and_then(|data| data.get(OWN_DATA_KEY))
+        .is_some();
+    (is_resolve && is_own).then(|| request.params.clone())
+}
+
 pub async fn handle_request<R: lsp_types::request::Request, F>(
     request: &mut lsp_server::Request,
     handler: impl FnOnce(Move<R::Params>) -> F,
@@ -36,7 +61,9 @@ pub async fn handle_response<R: lsp_types::request::Request, F>(
     }
     assert_eq!(request.id, response.id);
     if let Ok((id, request_params)) = request.clone().extract::<R::Params>(R::METHOD) {
-        if let Some(value) = response.clone().result {
+        if response.error.is_none() {
+            // A `null` result is deserialized as `None`.
+            let value = response.result.clone().unwrap_or_default();
             if let Ok(result) = serde_json::from_value(value) {
                 let result = handler(Move(request_params), Move(result)).await;
                 *response = lsp_server::Response::new_ok(id, result);
@@ -60,3 +87,36 @@ pub async fn handle_notification<N, F>(
             lsp_server::Notification::new(std::mem::take(&mut notification.method), params);
     }
 }
+pub async fn handle_response_result<R: lsp_types::request::Request, F>(
+    request: &lsp_server::Request,
+    response: &mut lsp_server::Response,
+    handler: impl FnOnce(Move<R::Params>, Move<Result<R::Result, lsp_server::ResponseError>>) -> F,
+) where
+    F: Future<Output = Result<R::Result, lsp_server::ResponseError>>,
+{
+    if request.method != R::METHOD {
+        return;
+    }
+    assert_eq!(request.id, response.id);
+    if let Ok((id, request_params)) = request.clone().extract::<R::Params>(R::METHOD) {
+        let result = match response.clone() {
+            lsp_server::Response {
+                error: Some(error), ..
+            } => Err(error),
+            lsp_server::Response { result, .. } => {
+                match serde_json::from_value(result.unwrap_or_default()) {
+                    Ok(result) => Ok(result),
+                    Err(_) => return,
+                }
+            }
+        };
+        *response = match handler(Move(request_params), Move(result)).await {
+            Ok(result) => lsp_server::Response::new_ok(id, result),
+            Err(error) => lsp_server::Response {
+                id,
+                result: None,
+                error: Some(error),
+            },
+        };
+    }
+}
@@ -0,0 +1,39 @@
+use lsp_types::{InlayHint, InlayHintLabel, Position, Range};
+
+use crate::{
+    document::{Document, Encoding},
+    lockfile::Lockfile,
+    manifest::Manifest,
+};
+
+/// Creates hints showing the resolved version after each dependency in the embedded manifest.
+pub fn version_hints(
+    document: &Document,
+    encoding: Encoding,
+    lockfile: &Lockfile,
+    range: Range,
+) -> Vec<InlayHint> {
+    let Some(manifest) = Manifest::find(document) else {
+        return vec![];
+    };
+    manifest
+        .dependencies()
+        .into_iter()
+        .filter_map(|dependency| {
+            let package = lockfile.resolve(&dependency.package)?;
+            let (line, _) = manifest.script_position(dependency.value_span.end)?;
+            let text = document.line(line)?;
+            let position = Position::new(line, encoding.str_len(text));
+            (range.start <= position && position <= range.end).then(|| InlayHint {
+                position,
+                label: InlayHintLabel::String(format!("→ {}", package.version)),
+                kind: None,
+                text_edits: None,
+                tooltip: None,
+                padding_left: Some(true),
+                padding_right: None,
+                data: None,
+            })
+        })
+        .collect()
+}
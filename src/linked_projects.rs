@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+
+use lsp_types::{DidChangeWatchedFilesParams, FileChangeType};
+use serde_json::{json, Value};
+
+/// Names of files rust-analyzer discovers as projects, in the order of preference.
+pub const MANIFESTS: [&str; 2] = ["rust-project.json", "Cargo.toml"];
+
+/// Finds projects in the directories or their children, as rust-analyzer does by itself unless
+/// `linkedProjects` is given, which we always do.
+pub async fn discover(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let roots = roots.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let mut projects = vec![];
+        for root in roots {
+            if let Some(project) = find(&root) {
+                projects.push(project);
+                continue;
+            }
+            let Ok(entries) = std::fs::read_dir(&root) else {
+                continue;
+            };
+            let mut children = entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    !name.starts_with('.') && name != "target"
+                })
+                .filter_map(|entry| find(&entry.path()))
+                .collect::<Vec<_>>();
+            children.sort();
+            projects.extend(children);
+        }
+        projects
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn find(dir: &Path) -> Option<PathBuf> {
+    MANIFESTS
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Returns whether projects may have appeared or disappeared.
+pub fn changed(params: &DidChangeWatchedFilesParams) -> bool {
+    params.changes.iter().any(|change| {
+        change.typ != FileChangeType::CHANGED
+            && MANIFESTS
+                .into_iter()
+                .any(|name| change.uri.path().ends_with(&format!("/{name}")))
+    })
+}
+
+/// Adds our projects to the ones the user linked, if any.
+pub fn merge(user: Option<&Value>, ours: Vec<Value>) -> Vec<Value> {
+    let mut projects = user.and_then(Value::as_array).cloned().unwrap_or_default();
+    for project in ours {
+        if !projects.contains(&project) {
+            projects.push(project);
+        }
+    }
+    if projects.is_empty() {
+        // Push a dummy project to prevent rust-analyzer from complaining missing projects.
+        projects.push(json!({
+            "crates": []
+        }));
+    }
+    projects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_with_user_projects() {
+        let user = json!(["/work/Cargo.toml", { "crates": [] }]);
+        assert_eq!(
+            merge(
+                Some(&user),
+                vec![json!("/work/Cargo.toml"), json!("/cache/script/Cargo.toml")],
+            ),
+            [
+                json!("/work/Cargo.toml"),
+                json!({ "crates": [] }),
+                json!("/cache/script/Cargo.toml"),
+            ],
+        );
+        assert_eq!(
+            merge(Some(&json!("invalid")), vec![json!("/a/Cargo.toml")]),
+            [json!("/a/Cargo.toml")],
+        );
+        assert_eq!(merge(None, vec![]), [json!({ "crates": [] })]);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, path::Path};
+
+use eyre::{eyre, Result, WrapErr as _};
+use lsp_types::Url;
+use toml_edit::{DocumentMut, Item};
+
+/// A package recorded in `Cargo.lock`.
+#[derive(Debug, Clone)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub source: Option<String>,
+    dependencies: Vec<String>,
+}
+
+/// The `Cargo.lock` of the package generated for a script.
+#[derive(Debug, Clone)]
+pub struct Lockfile {
+    root: Option<String>,
+    packages: Vec<LockedPackage>,
+}
+impl Lockfile {
+    /// Loads `Cargo.lock` next to the `Cargo.toml` at `manifest_path`.
+    pub async fn load(manifest_path: &Path) -> Result<Self> {
+        let lock_path = manifest_path.with_file_name("Cargo.lock");
+        let lock = tokio::fs::read_to_string(&lock_path)
+            .await
+            .wrap_err_with(|| eyre!("failed to read {lock_path:?}"))?;
+        let manifest = tokio::fs::read_to_string(manifest_path)
+            .await
+            .wrap_err_with(|| eyre!("failed to read {manifest_path:?}"))?;
+        Self::parse(&manifest, &lock)
+    }
+
+    /// Parses `Cargo.lock` along with `Cargo.toml` of the root package.
+    fn parse(manifest: &str, lock: &str) -> Result<Self> {
+        let root = manifest
+            .parse::<DocumentMut>()
+            .wrap_err("invalid Cargo.toml")?
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(Item::as_str)
+            .map(str::to_owned);
+        let packages = lock
+            .parse::<DocumentMut>()
+            .wrap_err("invalid Cargo.lock")?
+            .get("package")
+            .and_then(Item::as_array_of_tables)
+            .into_iter()
+            .flatten()
+            .filter_map(|package| {
+                let field = |field| package.get(field).and_then(Item::as_str).map(str::to_owned);
+                Some(LockedPackage {
+                    name: field("name")?,
+                    version: field("version")?,
+                    source: field("source"),
+                    dependencies: package
+                        .get("dependencies")
+                        .and_then(Item::as_array)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|dependency| dependency.as_str().map(str::to_owned))
+                        .collect(),
+                })
+            })
+            .collect();
+        Ok(Self { root, packages })
+    }
+
+    /// Finds the package the root package depends on with the package name `name`.
+    pub fn resolve(&self, name: &str) -> Option<&LockedPackage> {
+        let mut candidates = self.packages.iter().filter(|package| package.name == name);
+        let first = candidates.next()?;
+        let Some(root) = self
+            .root
+            .as_ref()
+            .and_then(|root| self.packages.iter().find(|package| &package.name == root))
+        else {
+            return Some(first);
+        };
+        // Entries are `name`, `name version` or `name version (source)`, where the version is
+        // omitted unless multiple versions of the package exist.
+        let version = root.dependencies.iter().find_map(|dependency| {
+            let mut fields = dependency.split_whitespace();
+            (fields.next() == Some(name)).then(|| fields.next())
+        })?;
+        match version {
+            None => Some(first),
+            Some(version) => std::iter::once(first)
+                .chain(candidates)
+                .find(|package| package.version == version),
+        }
+    }
+}
+
+/// Lockfiles of the packages generated for scripts, kept until the packages are loaded again, as
+/// inlay hints and document links ask for them on every scroll and edit.
+#[derive(Default)]
+pub struct Cache(HashMap<Url, Lockfile>);
+impl Cache {
+    /// Returns the lockfile of the script, loading it from `manifest_path` unless cached.
+    pub async fn get(&mut self, script: &Url, manifest_path: &Path) -> Result<&Lockfile> {
+        if !self.0.contains_key(script) {
+            let lockfile = Lockfile::load(manifest_path).await?;
+            self.0.insert(script.clone(), lockfile);
+        }
+        Ok(&self.0[script])
+    }
+
+    pub fn forget(&mut self, script: &Url) {
+        self.0.remove(script);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"
+[[package]]
+name = "script"
+version = "0.1.0"
+dependencies = [
+ "itoa 1.0.11",
+ "serde",
+]
+
+[[package]]
+name = "itoa"
+version = "0.4.8"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "itoa"
+version = "1.0.11"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "serde"
+version = "1.0.200"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+dependencies = [
+ "itoa 0.4.8",
+]
+"#;
+
+    #[test]
+    fn resolve_versions_of_root_dependencies() {
+        let lockfile = Lockfile::parse("[package]\nname = \"script\"\n", LOCK).unwrap();
+        let version = |name| {
+            lockfile
+                .resolve(name)
+                .map(|package| package.version.as_str())
+        };
+        assert_eq!(version("itoa"), Some("1.0.11"));
+        assert_eq!(version("serde"), Some("1.0.200"));
+        assert_eq!(version("script"), None);
+        assert_eq!(version("tokio"), None);
+    }
+
+    #[test]
+    fn resolve_without_root() {
+        let lockfile = Lockfile::parse("", LOCK).unwrap();
+        let version = |name| {
+            lockfile
+                .resolve(name)
+                .map(|package| package.version.as_str())
+        };
+        assert_eq!(version("itoa"), Some("0.4.8"));
+        assert_eq!(version("serde"), Some("1.0.200"));
+    }
+}
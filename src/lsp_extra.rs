@@ -1,4 +1,6 @@
-use lsp_types::request::Request;
+use lsp_types::{request::Request, Location, Position, TextDocumentIdentifier, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub trait MessageExt {
     fn is_exit(&self) -> bool;
@@ -17,3 +19,56 @@ impl Request for ReloadWorkspace {
     type Result = ();
     const METHOD: &'static str = "rust-analyzer/reloadWorkspace";
 }
+
+#[derive(Debug)]
+pub enum Runnables {}
+impl Request for Runnables {
+    type Params = RunnablesParams;
+    // Kept untyped to pass through whatever rust-analyzer adds.
+    type Result = Vec<Value>;
+    const METHOD: &'static str = "experimental/runnables";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunnablesParams {
+    pub text_document: TextDocumentIdentifier,
+    pub position: Option<Position>,
+}
+
+#[derive(Debug)]
+pub enum OpenCargoToml {}
+impl Request for OpenCargoToml {
+    type Params = OpenCargoTomlParams;
+    type Result = Option<Location>;
+    const METHOD: &'static str = "rust-analyzer/openCargoToml";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenCargoTomlParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+/// Reads a file of a package generated by rust-script, see [crate::generated].
+#[derive(Debug)]
+pub enum GeneratedFile {}
+impl Request for GeneratedFile {
+    type Params = GeneratedFileParams;
+    type Result = GeneratedFileResult;
+    const METHOD: &'static str = "rscls/generatedFile";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeneratedFileParams {
+    pub uri: Url,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeneratedFileResult {
+    pub text: String,
+    /// The version of rust-script that generated the file, if known.
+    pub rust_script_version: Option<String>,
+}
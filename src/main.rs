@@ -1,35 +1,61 @@
-use std::{
-    collections::{HashMap, HashSet},
-    fs::OpenOptions,
-    io::Write,
-    path::PathBuf,
-};
+use std::{collections::HashMap, fs::OpenOptions, io::Write, path::PathBuf};
 
 use clap::Parser;
 use eyre::{eyre, Result, WrapErr as _};
-use lsp_server::Message;
+use lsp_server::{ErrorCode, Message};
 use lsp_types::{
     notification::{self, Notification as _},
-    request,
+    request::{self, Request as _},
+    SemanticTokensFullDeltaResult, SemanticTokensRangeResult, SemanticTokensResult,
 };
 use serde_json::{json, Value};
 use verbosity::Verbosity;
 
 use crate::{
     client::Client,
-    handler::{handle_notification, handle_request, handle_response, Move},
+    document::{Documents, Encoding},
+    handler::{
+        handle_notification, handle_request, handle_response, handle_response_result, own_data,
+        resolve_own, Move,
+    },
     lsp_extra::MessageExt as _,
     script::Scripts,
     server::Server,
+    settings::Settings,
 };
 
 mod client;
+mod code_action;
+mod code_lens;
 mod codec;
+mod commands;
+mod detection;
+mod diagnostics;
+mod directive;
+mod document;
+mod document_link;
+mod eject;
 mod event;
+mod format;
+mod generated;
 mod handler;
+mod inlay_hint;
+mod linked_projects;
+mod lockfile;
 mod lsp_extra;
+mod manifest;
+mod new_script;
+mod open_cargo_toml;
+mod progress;
+mod project_file;
+mod ra_toml;
+mod runnables;
+mod runner;
 mod script;
+mod semantic_tokens;
 mod server;
+mod settings;
+mod shebang;
 mod verbosity;
 
 #[derive(Parser, Debug)]
@@ -47,6 +73,20 @@ struct Args {
     #[arg(long, default_value = "rustc")]
     rustc: PathBuf,
 
+    /// The directory containing templates for new scripts, e.g. `tokio.ers`.
+    #[arg(long)]
+    template_dir: Option<PathBuf>,
+
+    /// A directory whose project files (`rscls.toml`) may set programs to run.
+    #[arg(long = "trust", value_name = "DIR")]
+    trusted_dirs: Vec<PathBuf>,
+
+    /// Adjusts how a program is run: `<TOOL>` is `rust-analyzer`, `rust-script`, `rustc`, `cargo`
+    /// or `all`, and `<FIELD>` is `arg` (repeatable), `env` (`KEY=VALUE`), `cwd` or `wrapper`
+    /// (split by whitespace), e.g. `--profile 'all.wrapper=nix develop -c'`.
+    #[arg(long = "profile", value_name = "TOOL.FIELD=VALUE", value_parser = parse_profile)]
+    profiles: Vec<String>,
+
     /// The file to use as the log output instead of stderr.
     #[arg(short('o'), long)]
     log_file: Option<PathBuf>,
@@ -55,6 +95,13 @@ struct Args {
     verbosity: Verbosity<verbosity::WarnLevel>,
 }
 
+fn parse_profile(arg: &str) -> Result<String, String> {
+    settings::Profiles::default()
+        .apply_arg(arg)
+        .map(|()| arg.to_owned())
+        .map_err(|e| e.to_string())
+}
+
 fn init_tracing_subscriber(args: &Args) {
     let fmt = tracing_subscriber::fmt()
         .with_max_level(args.verbosity.level_filter())
@@ -71,21 +118,162 @@ fn init_tracing_subscriber(args: &Args) {
     }
 }
 
-fn modify_config(opts: &mut Value, mut rust_projects: Vec<Value>) {
+fn modify_config(opts: &mut Value, rust_projects: Vec<Value>, settings: &Settings) {
     if opts.is_null() {
         *opts = json!({});
     }
+    settings.fill_rust_analyzer_settings(opts);
     if let Some(opts) = opts.as_object_mut() {
-        if rust_projects.is_empty() {
-            // Push a dummy project to prevent rust-analyzer from complaining missing projects.
-            rust_projects.push(json!({
-                "crates": []
-            }))
-        }
+        let rust_projects = linked_projects::merge(opts.get("linkedProjects"), rust_projects);
         opts.insert("linkedProjects".to_owned(), Value::Array(rust_projects));
     }
 }
 
+/// Returns the projects to link along with the ones the user did, i.e. the scripts and the ones
+/// rust-analyzer would discover by itself.
+async fn linked_projects(scripts: &Scripts, roots: &[PathBuf], settings: &Settings) -> Vec<Value> {
+    let mut projects = scripts.projects().await;
+    if settings.discover_projects {
+        projects.extend(
+            linked_projects::discover(roots)
+                .await
+                .iter()
+                .map(|path| json!(path)),
+        );
+    }
+    projects
+}
+
+fn modify_capabilities(
+    capabilities: &mut Value,
+    documents: &mut Documents,
+    semantic_tokens: &mut semantic_tokens::Overlay,
+    features: settings::Features,
+) {
+    semantic_tokens.set_legend(capabilities);
+    if let Some(encoding) = capabilities
+        .get("positionEncoding")
+        .and_then(|encoding| serde_json::from_value(encoding.clone()).ok())
+    {
+        documents.set_encoding(Encoding::from_kind(&encoding));
+    }
+    if let Some(capabilities) = capabilities.as_object_mut() {
+        // We can format the manifest even if rustfmt can't format ranges.
+        if features.formatting {
+            capabilities.insert("documentRangeFormattingProvider".to_owned(), json!(true));
+        }
+        // rust-analyzer doesn't provide links, but we do for the manifest.
+        capabilities
+            .entry("documentLinkProvider")
+            .or_insert_with(|| json!({ "resolveProvider": false }));
+        let provider = capabilities
+            .entry("executeCommandProvider")
+            .or_insert_with(|| json!({ "commands": [] }));
+        if let Some(commands) = provider
+            .get_mut("commands")
+            .and_then(|commands| commands.as_array_mut())
+        {
+            commands.extend(commands::COMMANDS.map(Value::from));
+        }
+    }
+}
+
+/// Returns the `rscls` section given by the client, or `null` letting the user know if it's
+/// invalid.
+fn checked_section(section: Option<Value>, settings: &settings::Shared, client: &Client) -> Value {
+    let section = section.unwrap_or_default();
+    match settings.layers().command_line.merge(&section) {
+        Ok(_) => section,
+        Err(e) => {
+            tracing::warn!(?e, "ignoring invalid settings");
+            client
+                .handle()
+                .notify::<notification::ShowMessage>(lsp_types::ShowMessageParams {
+                    typ: lsp_types::MessageType::WARNING,
+                    message: format!("Ignoring invalid `{}` settings: {e:#}", settings::SECTION),
+                });
+            Value::Null
+        }
+    }
+}
+
+/// Reads project files around the directories, and returns the previous layers if any changed.
+async fn load_project_files(
+    dirs: Vec<PathBuf>,
+    settings: &settings::Shared,
+    client: &Client,
+) -> Option<settings::Layers> {
+    let layers = settings.layers();
+    let changes = project_file::load(&client.handle(), dirs, &layers).await;
+    if changes.is_empty() {
+        return None;
+    }
+    Some(settings.update(|layers| {
+        for (dir, file) in changes {
+            match file {
+                Some(file) => layers.files.insert(dir, file),
+                None => layers.files.remove(&dir),
+            };
+        }
+    }))
+}
+
+/// Applies the change of the settings from `old`.
+fn reconfigure(
+    old_layers: &settings::Layers,
+    settings: &settings::Shared,
+    scripts: &Scripts,
+    diagnostics: &mut diagnostics::Diagnostics,
+    client: &Client,
+    event_sender: &event::EventSender,
+    server_started: bool,
+) {
+    // Project files may change settings of some scripts only.
+    scripts.reconfigure(old_layers);
+    let (old, new) = (old_layers.resolve(None), settings.get());
+    if old == *new {
+        return;
+    }
+    tracing::info!(?new, "settings changed");
+    for params in diagnostics.set_enabled(new.features.diagnostics) {
+        client
+            .handle()
+            .notify::<notification::PublishDiagnostics>(params);
+    }
+    if old.rust_analyzer_settings != new.rust_analyzer_settings || old.target_dir != new.target_dir
+    {
+        event_sender.mark_need_reload();
+    }
+    if server_started
+        && (old.rust_analyzer != new.rust_analyzer
+            || old.profiles.rust_analyzer != new.profiles.rust_analyzer)
+    {
+        client
+            .handle()
+            .notify::<notification::ShowMessage>(lsp_types::ShowMessageParams {
+                typ: lsp_types::MessageType::WARNING,
+                message: format!("Restart rscls to use {}", new.rust_analyzer.display()),
+            });
+    }
+}
+
+/// Checks the shebang of the script again, looking up the interpreter off the main loop to be
+/// reported as [event::Event::ShebangLookedUp].
+fn check_script(uri: &lsp_types::Url, documents: &Documents, event_sender: &event::EventSender) {
+    let (Some(document), Ok(path)) = (documents.get(uri), uri.to_file_path()) else {
+        return;
+    };
+    let first_line = document.line(0).unwrap_or_default().to_owned();
+    let script = uri.clone();
+    let event_sender = event_sender.clone();
+    tokio::spawn(async move {
+        let lookup = shebang::lookup(&first_line, path).await;
+        event_sender
+            .send(event::Event::ShebangLookedUp { script, lookup })
+            .ok();
+    });
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -94,36 +282,151 @@ async fn main() -> Result<()> {
     tracing::debug!(?args);
 
     let (event_sender, mut event_receiver) = event::new_event_bus();
-    let language_ids = {
-        let mut language_ids = HashSet::new();
-        language_ids.insert("rustscript");
-        language_ids.insert("rust-script");
-        language_ids.insert("rust_script");
-        language_ids
-    };
+    let mut profiles = settings::Profiles::default();
+    for arg in &args.profiles {
+        profiles.apply_arg(arg)?;
+    }
+    let settings = settings::Shared::new(Settings {
+        profiles,
+        template_dir: args.template_dir.clone(),
+        trusted_dirs: args.trusted_dirs.clone(),
+        ..Settings::new(
+            args.rust_script.clone(),
+            args.rust_analyzer.clone(),
+            args.rustc.clone(),
+        )
+    });
 
     let client = Client::stdio(event_sender.clone());
-    let server = Server::spawn(event_sender.clone(), args.rust_analyzer)
-        .wrap_err("failed to spawn server")?;
+    let mut workspace_roots = vec![];
+    // Spawned on the first message, to use the path in `initializationOptions` if any.
+    let mut server: Option<Server> = None;
 
-    let mut scripts = Scripts::new(event_sender.clone(), args.rustc, args.rust_script)?;
+    let mut scripts = Scripts::new(
+        client.handle(),
+        event_sender.clone(),
+        settings.clone(),
+        args.log_file.clone(),
+    )?;
+    let mut documents = Documents::default();
+    let mut semantic_tokens = semantic_tokens::Overlay::default();
+    let mut lockfiles = lockfile::Cache::default();
+    let mut diagnostics = diagnostics::Diagnostics::default();
+    // The latest lookup of the interpreter of each script, for quick fixes of the shebang.
+    let mut shebang_lookups = HashMap::new();
+    let mut requests_from_client = HashMap::new();
     let mut requests_from_server = HashMap::new();
     let mut no_need_reload_version = event_sender.current_version();
+    // The latest settings of rust-analyzer given by the client, to send along with ours on reload.
+    let mut user_settings = Value::Null;
     while let Some(event) = event_receiver.recv().await {
         // Need async non-move closure https://github.com/rust-lang/rust/issues/62290
         match event {
             event::Event::ClientToServer(mut message) => {
                 tracing::debug!(?message, "Message from client");
+                if matches!(&message, Message::Response(response) if client.complete(response)) {
+                    continue;
+                }
                 match &mut message {
                     Message::Request(ref mut request) => {
+                        if let Some(params) = commands::own_command(request) {
+                            let context = commands::Context {
+                                client: client.handle(),
+                                documents: documents
+                                    .iter()
+                                    .map(|(uri, document)| (uri.clone(), document.clone()))
+                                    .collect(),
+                                workspace_roots: workspace_roots.clone(),
+                                event_sender: event_sender.clone(),
+                                settings: settings.clone(),
+                            };
+                            let id = request.id.clone();
+                            let client = client.handle();
+                            tokio::spawn(async move {
+                                client.respond(id, commands::execute(params, context).await);
+                            });
+                            continue;
+                        }
+                        if let Some(item) = resolve_own(request) {
+                            client.handle().respond(request.id.clone(), Ok(item));
+                            continue;
+                        }
+                        if let Some(params) = generated::own_request(request) {
+                            let id = request.id.clone();
+                            let client = client.handle();
+                            let package_dirs = scripts.package_dirs();
+                            let settings = settings.get();
+                            tokio::spawn(async move {
+                                let result = generated::read(params, package_dirs, &settings).await;
+                                client.respond(id, result);
+                            });
+                            continue;
+                        }
+                        if let Some((uri, document)) = open_cargo_toml::script(request, &documents)
+                        {
+                            let id = request.id.clone();
+                            let client = client.handle();
+                            let encoding = documents.encoding();
+                            tokio::spawn(async move {
+                                let result =
+                                    open_cargo_toml::open(client.clone(), uri, document, encoding)
+                                        .await;
+                                client.respond(id, result);
+                            });
+                            continue;
+                        }
+                        requests_from_client.insert(request.id.clone(), request.clone());
                         handle_request::<request::Initialize, _>(
                             request,
                             |Move(mut params)| async {
+                                client.set_capabilities(params.capabilities.clone());
+                                workspace_roots = match &params.workspace_folders {
+                                    Some(folders) => folders
+                                        .iter()
+                                        .filter_map(|folder| folder.uri.to_file_path().ok())
+                                        .collect(),
+                                    #[allow(deprecated)]
+                                    None => params
+                                        .root_uri
+                                        .iter()
+                                        .filter_map(|uri| uri.to_file_path().ok())
+                                        .collect(),
+                                };
                                 let opts = params
                                     .initialization_options
                                     .get_or_insert_with(|| json!({}));
+                                // rust-analyzer doesn't know about our section.
+                                let section = opts
+                                    .as_object_mut()
+                                    .and_then(|opts| opts.remove(settings::SECTION));
+                                user_settings = opts.clone();
+                                let section = checked_section(section, &settings, &client);
+                                let mut old = settings.update(|layers| {
+                                    layers.initialization_options = section;
+                                    layers.roots = workspace_roots.clone();
+                                });
+                                if let Some(older) =
+                                    load_project_files(workspace_roots.clone(), &settings, &client)
+                                        .await
+                                {
+                                    old = older;
+                                }
+                                reconfigure(
+                                    &old,
+                                    &settings,
+                                    &scripts,
+                                    &mut diagnostics,
+                                    &client,
+                                    &event_sender,
+                                    false,
+                                );
                                 no_need_reload_version = event_sender.start_reload();
-                                modify_config(opts, scripts.projects().await);
+                                modify_config(
+                                    opts,
+                                    linked_projects(&scripts, &workspace_roots, &settings.get())
+                                        .await,
+                                    &settings.get(),
+                                );
                                 params
                             },
                         )
@@ -133,6 +436,10 @@ async fn main() -> Result<()> {
                             params.moved()
                         })
                         .await;
+                        // Even if the overlay is disabled, so that it can be enabled at any time.
+                        if request.method == request::SemanticTokensFullDeltaRequest::METHOD {
+                            semantic_tokens::request_full_instead_of_delta(request, &documents);
+                        }
                         // TODO: Other ones
                     }
                     Message::Response(ref mut response) => {
@@ -143,14 +450,77 @@ async fn main() -> Result<()> {
                         handle_response::<request::WorkspaceConfiguration, _>(
                             &request,
                             response,
-                            |Move(params), Move(mut result)| async {
+                            |params, Move(mut result)| async {
+                                let mut params = params.moved();
+                                // The last item is ours if asked along with rust-analyzer's.
+                                let ours = params.items.last().is_some_and(|item| {
+                                    item.section.as_deref() == Some(settings::SECTION)
+                                });
+                                if ours && result.len() == params.items.len() {
+                                    params.items.pop();
+                                    let section = checked_section(result.pop(), &settings, &client);
+                                    let old =
+                                        settings.update(|layers| layers.configuration = section);
+                                    reconfigure(
+                                        &old,
+                                        &settings,
+                                        &scripts,
+                                        &mut diagnostics,
+                                        &client,
+                                        &event_sender,
+                                        true,
+                                    );
+                                    // Then the ones for scripts, as rust-analyzer doesn't specify
+                                    // scope_uri currently.
+                                    let mut scoped = vec![];
+                                    while let Some(uri) = params.items.last().and_then(|item| {
+                                        (item.section.as_deref() == Some("rust-analyzer"))
+                                            .then(|| item.scope_uri.clone())
+                                            .flatten()
+                                    }) {
+                                        params.items.pop();
+                                        scoped.extend(result.pop().map(|value| (uri, value)));
+                                    }
+                                    let workspace = params
+                                        .items
+                                        .iter()
+                                        .position(|item| {
+                                            item.scope_uri.is_none()
+                                                && item.section.as_deref() == Some("rust-analyzer")
+                                        })
+                                        .and_then(|i| result.get(i));
+                                    for (uri, value) in scoped {
+                                        let value = match workspace {
+                                            Some(workspace) => settings::diff(workspace, &value),
+                                            None => value,
+                                        };
+                                        scripts.set_scoped_settings(&uri, value);
+                                    }
+                                }
                                 for (i, item) in params.items.into_iter().enumerate() {
-                                    // NOTE: Semantically we should probably handle scope_uri but
-                                    // rust-analyzer doesn't specify them currenlty.
                                     if Some("rust-analyzer") == item.section.as_deref() {
                                         if let Some(value) = result.get_mut(i) {
+                                            match item.scope_uri {
+                                                None => user_settings = value.clone(),
+                                                Some(uri) => {
+                                                    if let Some(script_settings) =
+                                                        scripts.rust_analyzer_settings(&uri)
+                                                    {
+                                                        settings::merge(value, &script_settings);
+                                                    }
+                                                }
+                                            }
                                             no_need_reload_version = event_sender.start_reload();
-                                            modify_config(value, scripts.projects().await)
+                                            modify_config(
+                                                value,
+                                                linked_projects(
+                                                    &scripts,
+                                                    &workspace_roots,
+                                                    &settings.get(),
+                                                )
+                                                .await,
+                                                &settings.get(),
+                                            )
                                         }
                                     }
                                 }
@@ -160,22 +530,221 @@ async fn main() -> Result<()> {
                         .await;
                     }
                     Message::Notification(ref mut notification) => {
+                        if let Ok(params) = notification
+                            .clone()
+                            .extract::<lsp_types::WorkDoneProgressCancelParams>(
+                            notification::WorkDoneProgressCancel::METHOD,
+                        ) {
+                            if client.cancel_progress(&params.token) {
+                                continue;
+                            }
+                        }
                         handle_notification::<notification::DidOpenTextDocument, _>(
                             notification,
                             |Move(mut params)| async {
-                                if language_ids.contains(params.text_document.language_id.as_str())
-                                {
+                                let is_script =
+                                    params.text_document.uri.to_file_path().is_ok_and(|path| {
+                                        detection::is_script(
+                                            &settings.for_path(&path),
+                                            &workspace_roots,
+                                            &params.text_document,
+                                        )
+                                    });
+                                if is_script {
+                                    let dirs = params
+                                        .text_document
+                                        .uri
+                                        .to_file_path()
+                                        .ok()
+                                        .and_then(|path| Some(path.parent()?.to_path_buf()))
+                                        .into_iter()
+                                        .collect();
+                                    if let Some(old) =
+                                        load_project_files(dirs, &settings, &client).await
+                                    {
+                                        reconfigure(
+                                            &old,
+                                            &settings,
+                                            &scripts,
+                                            &mut diagnostics,
+                                            &client,
+                                            &event_sender,
+                                            true,
+                                        );
+                                    }
                                     scripts.register(params.text_document.uri.clone()).await;
+                                    documents.open(
+                                        params.text_document.uri.clone(),
+                                        params.text_document.version,
+                                        params.text_document.text.clone(),
+                                    );
+                                    diagnostics.open(&params.text_document.uri);
+                                    check_script(
+                                        &params.text_document.uri,
+                                        &documents,
+                                        &event_sender,
+                                    );
                                     params.text_document.language_id = "rust".to_owned();
                                 }
                                 params
                             },
                         )
                         .await;
+                        handle_notification::<notification::DidChangeTextDocument, _>(
+                            notification,
+                            |Move(params)| async {
+                                documents.change(
+                                    &params.text_document.uri,
+                                    params.text_document.version,
+                                    params.content_changes.clone(),
+                                );
+                                // Only the shebang matters, so keep the diagnostics unless it may
+                                // have changed, rather than searching `PATH` on every keystroke.
+                                let touches_shebang = params.content_changes.iter().any(|change| {
+                                    change.range.is_none_or(|range| range.start.line == 0)
+                                });
+                                if touches_shebang {
+                                    check_script(
+                                        &params.text_document.uri,
+                                        &documents,
+                                        &event_sender,
+                                    );
+                                }
+                                params
+                            },
+                        )
+                        .await;
+                        handle_notification::<notification::DidChangeWorkspaceFolders, _>(
+                            notification,
+                            |Move(params)| async {
+                                let paths = |folders: &[lsp_types::WorkspaceFolder]| {
+                                    folders
+                                        .iter()
+                                        .filter_map(|folder| folder.uri.to_file_path().ok())
+                                        .collect::<Vec<_>>()
+                                };
+                                let removed = paths(&params.event.removed);
+                                workspace_roots.retain(|root| !removed.contains(root));
+                                workspace_roots.extend(paths(&params.event.added));
+                                let mut old = settings.update(|layers| {
+                                    layers.roots = workspace_roots.clone();
+                                });
+                                if settings.get().discover_projects {
+                                    event_sender.mark_need_reload();
+                                }
+                                if let Some(older) = load_project_files(
+                                    paths(&params.event.added),
+                                    &settings,
+                                    &client,
+                                )
+                                .await
+                                {
+                                    old = older;
+                                }
+                                reconfigure(
+                                    &old,
+                                    &settings,
+                                    &scripts,
+                                    &mut diagnostics,
+                                    &client,
+                                    &event_sender,
+                                    true,
+                                );
+                                params
+                            },
+                        )
+                        .await;
                         handle_notification::<notification::DidCloseTextDocument, _>(
                             notification,
                             |Move(params)| async {
                                 scripts.deregister_if_registered(&params.text_document.uri);
+                                documents.close(&params.text_document.uri);
+                                semantic_tokens.forget(&params.text_document.uri);
+                                lockfiles.forget(&params.text_document.uri);
+                                shebang_lookups.remove(&params.text_document.uri);
+                                if let Some(params) = diagnostics.close(&params.text_document.uri) {
+                                    client
+                                        .handle()
+                                        .notify::<notification::PublishDiagnostics>(params);
+                                }
+                                params
+                            },
+                        )
+                        .await;
+                        handle_notification::<notification::DidChangeConfiguration, _>(
+                            notification,
+                            |Move(mut params)| async {
+                                // Some clients push the whole settings rather than letting
+                                // rust-analyzer pull them.
+                                let Some(sections) = params.settings.as_object_mut() else {
+                                    return params;
+                                };
+                                if let Some(section) = sections.remove(settings::SECTION) {
+                                    let section =
+                                        checked_section(Some(section), &settings, &client);
+                                    let old =
+                                        settings.update(|layers| layers.configuration = section);
+                                    reconfigure(
+                                        &old,
+                                        &settings,
+                                        &scripts,
+                                        &mut diagnostics,
+                                        &client,
+                                        &event_sender,
+                                        true,
+                                    );
+                                }
+                                if let Some(opts) = sections.get_mut("rust-analyzer") {
+                                    user_settings = opts.clone();
+                                    no_need_reload_version = event_sender.start_reload();
+                                    modify_config(
+                                        opts,
+                                        linked_projects(
+                                            &scripts,
+                                            &workspace_roots,
+                                            &settings.get(),
+                                        )
+                                        .await,
+                                        &settings.get(),
+                                    );
+                                }
+                                params
+                            },
+                        )
+                        .await;
+                        handle_notification::<notification::Initialized, _>(
+                            notification,
+                            |params| async {
+                                tokio::spawn(project_file::watch(client.handle()));
+                                params.moved()
+                            },
+                        )
+                        .await;
+                        handle_notification::<notification::DidChangeWatchedFiles, _>(
+                            notification,
+                            |Move(params)| async {
+                                if settings.get().discover_projects
+                                    && linked_projects::changed(&params)
+                                {
+                                    event_sender.mark_need_reload();
+                                }
+                                let dirs = project_file::changed_dirs(&params);
+                                if dirs.is_empty() {
+                                    return params;
+                                }
+                                if let Some(old) =
+                                    load_project_files(dirs, &settings, &client).await
+                                {
+                                    reconfigure(
+                                        &old,
+                                        &settings,
+                                        &scripts,
+                                        &mut diagnostics,
+                                        &client,
+                                        &event_sender,
+                                        true,
+                                    );
+                                }
                                 params
                             },
                         )
@@ -184,7 +753,17 @@ async fn main() -> Result<()> {
                         handle_notification::<notification::DidSaveTextDocument, _>(
                             notification,
                             |Move(params)| async {
-                                scripts.queue_refresh(&params.text_document.uri).await;
+                                let refresh = params
+                                    .text_document
+                                    .uri
+                                    .to_file_path()
+                                    .map_or(settings.get().refresh, |path| {
+                                        settings.for_path(&path).refresh
+                                    });
+                                if refresh == settings::Refresh::OnSave {
+                                    scripts.queue_refresh(&params.text_document.uri).await;
+                                }
+                                check_script(&params.text_document.uri, &documents, &event_sender);
                                 params
                             },
                         )
@@ -192,6 +771,13 @@ async fn main() -> Result<()> {
                     }
                 }
                 let need_exit = message.is_exit();
+                if server.is_none() {
+                    server = Some(
+                        Server::spawn(event_sender.clone(), settings.get().rust_analyzer_command())
+                            .wrap_err("failed to spawn server")?,
+                    );
+                }
+                let server = server.as_ref().unwrap();
                 server.sender.send(message).wrap_err("server stopped")?;
                 if need_exit {
                     break;
@@ -201,10 +787,361 @@ async fn main() -> Result<()> {
                 tracing::debug!(?message, "Message from server");
                 match &mut message {
                     Message::Request(ref mut request) => {
+                        handle_request::<request::WorkspaceConfiguration, _>(
+                            request,
+                            |Move(mut params)| async {
+                                // Ask ours along with rust-analyzer's, which it asks whenever the
+                                // client notifies changes.
+                                let asks_rust_analyzer = params
+                                    .items
+                                    .iter()
+                                    .any(|item| item.section.as_deref() == Some("rust-analyzer"));
+                                if asks_rust_analyzer {
+                                    // Settings of scripts, to find ones the user gave only for
+                                    // them, e.g. by language or folder, through `scopeUri`.
+                                    params.items.extend(scripts.uris().into_iter().map(|uri| {
+                                        lsp_types::ConfigurationItem {
+                                            scope_uri: Some(uri),
+                                            section: Some("rust-analyzer".to_owned()),
+                                        }
+                                    }));
+                                    params.items.push(lsp_types::ConfigurationItem {
+                                        scope_uri: None,
+                                        section: Some(settings::SECTION.to_owned()),
+                                    });
+                                }
+                                params
+                            },
+                        )
+                        .await;
                         requests_from_server.insert(request.id.clone(), request.clone());
                     }
-                    Message::Response(_response) => {}
-                    Message::Notification(_notification) => {}
+                    Message::Response(response) => {
+                        let features = settings.get().features;
+                        if let Some(request) = requests_from_client.remove(&response.id) {
+                            if request.method == request::Initialize::METHOD {
+                                if let Some(capabilities) = response
+                                    .result
+                                    .as_mut()
+                                    .and_then(|result| result.get_mut("capabilities"))
+                                {
+                                    modify_capabilities(
+                                        capabilities,
+                                        &mut documents,
+                                        &mut semantic_tokens,
+                                        features,
+                                    );
+                                }
+                            }
+                            if features.formatting {
+                                handle_response::<request::Formatting, _>(
+                                    &request,
+                                    response,
+                                    |Move(params), Move(mut result)| async {
+                                        let uri = params.text_document.uri;
+                                        if let Some(edit) =
+                                            documents.get(&uri).and_then(format::manifest_edit)
+                                        {
+                                            format::merge_edits(
+                                                result.get_or_insert_with(Vec::new),
+                                                edit,
+                                            );
+                                        }
+                                        result
+                                    },
+                                )
+                                .await;
+                            }
+                            if features.inlay_hints {
+                                handle_response::<request::InlayHintRequest, _>(
+                                    &request,
+                                    response,
+                                    |params, Move(mut result)| async {
+                                        let params = params.moved();
+                                        let uri = &params.text_document.uri;
+                                        let (Some(document), Some(manifest_path)) =
+                                            (documents.get(uri), scripts.manifest_path(uri))
+                                        else {
+                                            return result;
+                                        };
+                                        match lockfiles.get(uri, &manifest_path).await {
+                                            Ok(lockfile) => {
+                                                let hints = inlay_hint::version_hints(
+                                                    document,
+                                                    documents.encoding(),
+                                                    lockfile,
+                                                    params.range,
+                                                );
+                                                result.get_or_insert_with(Vec::new).extend(hints);
+                                            }
+                                            Err(e) => tracing::debug!(
+                                                ?e,
+                                                "no lockfile to show resolved versions"
+                                            ),
+                                        }
+                                        result
+                                    },
+                                )
+                                .await;
+                            }
+                            handle_response::<request::SemanticTokensFullRequest, _>(
+                                &request,
+                                response,
+                                |Move(params), Move(result)| async {
+                                    let uri = params.text_document.uri;
+                                    if !features.semantic_tokens {
+                                        semantic_tokens.forget(&uri);
+                                        return result;
+                                    }
+                                    match (result, documents.get(&uri)) {
+                                        (
+                                            Some(SemanticTokensResult::Tokens(tokens)),
+                                            Some(document),
+                                        ) => Some(SemanticTokensResult::Tokens(
+                                            semantic_tokens.full(
+                                                &uri,
+                                                document,
+                                                documents.encoding(),
+                                                tokens,
+                                            ),
+                                        )),
+                                        (result, _) => result,
+                                    }
+                                },
+                            )
+                            .await;
+                            handle_response::<request::SemanticTokensFullDeltaRequest, _>(
+                                &request,
+                                response,
+                                |params, Move(result)| async {
+                                    let params = params.moved();
+                                    let uri = &params.text_document.uri;
+                                    if !features.semantic_tokens {
+                                        semantic_tokens.forget(uri);
+                                        return result;
+                                    }
+                                    match (result, documents.get(uri)) {
+                                        (
+                                            Some(SemanticTokensFullDeltaResult::Tokens(tokens)),
+                                            Some(document),
+                                        ) => Some(semantic_tokens.delta(
+                                            uri,
+                                            &params.previous_result_id,
+                                            document,
+                                            documents.encoding(),
+                                            tokens,
+                                        )),
+                                        (result, _) => result,
+                                    }
+                                },
+                            )
+                            .await;
+                            if features.semantic_tokens {
+                                handle_response::<request::SemanticTokensRangeRequest, _>(
+                                    &request,
+                                    response,
+                                    |params, Move(result)| async {
+                                        let params = params.moved();
+                                        match (result, documents.get(&params.text_document.uri)) {
+                                            (
+                                                Some(SemanticTokensRangeResult::Tokens(tokens)),
+                                                Some(document),
+                                            ) => Some(SemanticTokensRangeResult::Tokens(
+                                                semantic_tokens.range(
+                                                    document,
+                                                    documents.encoding(),
+                                                    tokens,
+                                                    params.range.start.line
+                                                        ..params.range.end.line + 1,
+                                                ),
+                                            )),
+                                            (result, _) => result,
+                                        }
+                                    },
+                                )
+                                .await;
+                            }
+                            if features.document_links {
+                                handle_response_result::<request::DocumentLinkRequest, _>(
+                                    &request,
+                                    response,
+                                    |Move(params), Move(result)| async {
+                                        let uri = params.text_document.uri;
+                                        let result = match result {
+                                            Err(error)
+                                                if error.code
+                                                    == ErrorCode::MethodNotFound as i32 =>
+                                            {
+                                                Ok(None)
+                                            }
+                                            result => result,
+                                        };
+                                        let (Some(document), Ok(script)) =
+                                            (documents.get(&uri), uri.to_file_path())
+                                        else {
+                                            return result;
+                                        };
+                                        let lockfile = match scripts.manifest_path(&uri) {
+                                            Some(manifest_path) => {
+                                                lockfiles.get(&uri, &manifest_path).await.ok()
+                                            }
+                                            None => None,
+                                        };
+                                        let links = document_link::manifest_links(
+                                            document,
+                                            documents.encoding(),
+                                            &script,
+                                            lockfile,
+                                        )
+                                        .await;
+                                        result.map(|result| {
+                                            let mut result = result.unwrap_or_default();
+                                            result.extend(links);
+                                            Some(result)
+                                        })
+                                    },
+                                )
+                                .await;
+                            }
+                            if features.code_actions {
+                                handle_response::<request::CodeActionRequest, _>(
+                                    &request,
+                                    response,
+                                    |params, Move(mut result)| async {
+                                        let params = params.moved();
+                                        let uri = &params.text_document.uri;
+                                        if let Some(document) = documents.get(uri) {
+                                            let actions = code_action::convert_cargo_deps_actions(
+                                                uri,
+                                                document,
+                                                params.range,
+                                            )
+                                            .into_iter()
+                                            .chain(code_action::migrate_to_frontmatter_actions(
+                                                uri,
+                                                document,
+                                                params.range,
+                                            ))
+                                            .chain(code_action::evaluate_action(
+                                                uri,
+                                                document,
+                                                documents.encoding(),
+                                                params.range,
+                                            ))
+                                            .chain(shebang_lookups.get(uri).into_iter().flat_map(
+                                                |lookup| {
+                                                    shebang::fixes(
+                                                        uri,
+                                                        document,
+                                                        documents.encoding(),
+                                                        lookup,
+                                                        params.range,
+                                                    )
+                                                },
+                                            ))
+                                            .filter(|action| {
+                                                code_action::is_requested(
+                                                    params.context.only.as_deref(),
+                                                    action,
+                                                )
+                                            })
+                                            .map(|action| lsp_types::CodeAction {
+                                                data: Some(own_data()),
+                                                ..action
+                                            });
+                                            result
+                                                .get_or_insert_with(Vec::new)
+                                                .extend(actions.map(Into::into));
+                                        }
+                                        result
+                                    },
+                                )
+                                .await;
+                            }
+                            handle_response::<request::CodeLensRequest, _>(
+                                &request,
+                                response,
+                                |Move(params), Move(mut result)| async {
+                                    let uri = params.text_document.uri;
+                                    if let (Some(document), Ok(script)) =
+                                        (documents.get(&uri), uri.to_file_path())
+                                    {
+                                        let lenses = result.get_or_insert_with(Vec::new);
+                                        let script_settings = settings.for_path(&script);
+                                        if features.runnables {
+                                            for lens in lenses.iter_mut() {
+                                                runnables::rewrite_lens(
+                                                    lens,
+                                                    &uri,
+                                                    &script_settings,
+                                                    &script,
+                                                );
+                                            }
+                                        }
+                                        if features.code_lenses {
+                                            lenses.push(code_lens::run_lens(&uri, document));
+                                        }
+                                    }
+                                    result
+                                },
+                            )
+                            .await;
+                            handle_response::<lsp_extra::Runnables, _>(
+                                &request,
+                                response,
+                                |Move(params), Move(mut result)| async {
+                                    let uri = params.text_document.uri;
+                                    if let (true, Some(_), Ok(script)) = (
+                                        features.runnables,
+                                        documents.get(&uri),
+                                        uri.to_file_path(),
+                                    ) {
+                                        let script_settings = settings.for_path(&script);
+                                        for runnable in &mut result {
+                                            runnables::rewrite(runnable, &script_settings, &script);
+                                        }
+                                    }
+                                    result
+                                },
+                            )
+                            .await;
+                            if features.formatting {
+                                handle_response_result::<request::RangeFormatting, _>(
+                                    &request,
+                                    response,
+                                    |params, Move(result)| async {
+                                        let params = params.moved();
+                                        let Some(edit) = documents
+                                            .get(&params.text_document.uri)
+                                            .and_then(format::manifest_edit)
+                                            .filter(|edit| {
+                                                edit.range.start <= params.range.end
+                                                    && params.range.start <= edit.range.end
+                                            })
+                                        else {
+                                            return result;
+                                        };
+                                        // rust-analyzer refuses to format ranges unless rustfmt
+                                        // supports it, but the manifest is still worth formatting.
+                                        let mut edits = result.ok().flatten().unwrap_or_default();
+                                        format::merge_edits(&mut edits, edit);
+                                        Ok(Some(edits))
+                                    },
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    Message::Notification(notification) => {
+                        handle_notification::<notification::PublishDiagnostics, _>(
+                            notification,
+                            |Move(mut params)| async {
+                                diagnostics.merge_server(&mut params);
+                                params
+                            },
+                        )
+                        .await;
+                    }
                 }
                 client.sender.send(message).wrap_err("client stopped")?;
             }
@@ -215,12 +1152,69 @@ async fn main() -> Result<()> {
                 .await
                 .unwrap();
             }
+            event::Event::Loaded { script, error } => {
+                // The package may have been generated again, resolving other versions.
+                lockfiles.forget(&script);
+                let own = error
+                    .map(|error| diagnostics::load_failure(documents.get(&script), error))
+                    .into_iter()
+                    .collect();
+                if let Some(params) = diagnostics.set_own(&script, diagnostics::Kind::Load, own) {
+                    client
+                        .handle()
+                        .notify::<notification::PublishDiagnostics>(params);
+                }
+            }
+            event::Event::Recheck(uri) => {
+                check_script(&uri, &documents, &event_sender);
+            }
+            event::Event::ShebangLookedUp { script, lookup } => {
+                // Closed in the meantime.
+                let Some(document) = documents.get(&script) else {
+                    continue;
+                };
+                let own = shebang::diagnostics(document, documents.encoding(), &lookup);
+                shebang_lookups.insert(script.clone(), lookup);
+                if let Some(params) = diagnostics.set_own(&script, diagnostics::Kind::Shebang, own)
+                {
+                    client
+                        .handle()
+                        .notify::<notification::PublishDiagnostics>(params);
+                }
+            }
+            event::Event::Ejected {
+                script,
+                manifest_path,
+            } => {
+                // It's no longer a script but a file of the project, left to rust-analyzer.
+                scripts.eject(&script, manifest_path);
+                documents.close(&script);
+                semantic_tokens.forget(&script);
+                shebang_lookups.remove(&script);
+                lockfiles.forget(&script);
+                if let Some(params) = diagnostics.close(&script) {
+                    client
+                        .handle()
+                        .notify::<notification::PublishDiagnostics>(params);
+                }
+            }
             event::Event::NeedReload(dirty_version) => {
                 if dirty_version < no_need_reload_version {
                     continue;
                 }
+                let Some(server) = &server else {
+                    continue;
+                };
+                // For clients not supporting `workspace/configuration`, with which rust-analyzer
+                // uses the settings as is.
+                let mut opts = user_settings.clone();
+                modify_config(
+                    &mut opts,
+                    linked_projects(&scripts, &workspace_roots, &settings.get()).await,
+                    &settings.get(),
+                );
                 let config = lsp_types::DidChangeConfigurationParams {
-                    settings: Default::default(),
+                    settings: json!({ "rust-analyzer": opts }),
                 };
                 let message = Message::Notification(lsp_server::Notification::new(
                     notification::DidChangeConfiguration::METHOD.to_owned(),
@@ -228,6 +1222,25 @@ async fn main() -> Result<()> {
                 ));
                 server.sender.send(message).wrap_err("server stopped")?;
                 no_need_reload_version = dirty_version;
+                let inlay_hint_refresh_support = client
+                    .handle()
+                    .capabilities()
+                    .workspace
+                    .as_ref()
+                    .and_then(|workspace| workspace.inlay_hint.as_ref())
+                    .and_then(|inlay_hint| inlay_hint.refresh_support)
+                    .unwrap_or(false);
+                // Resolved versions may have changed.
+                lockfiles.clear();
+                if inlay_hint_refresh_support {
+                    let client = client.handle();
+                    tokio::spawn(async move {
+                        if let Err(e) = client.request::<request::InlayHintRefreshRequest>(()).await
+                        {
+                            tracing::warn!(?e, "failed to refresh inlay hints");
+                        }
+                    });
+                }
             }
         }
     }
@@ -0,0 +1,392 @@
+use std::ops::Range;
+
+use toml_edit::{ImDocument, Item, Table};
+
+use crate::document::{Document, Encoding};
+
+const DOC_COMMENT: &str = "//!";
+
+pub const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// The short form manifest of a script, i.e. a `// cargo-deps: time="0.1", serde` line.
+#[derive(Debug, Clone)]
+pub struct CargoDeps {
+    pub line: u32,
+    /// Comma-separated dependencies after `cargo-deps:`.
+    pub deps: String,
+}
+
+impl CargoDeps {
+    /// Finds the `cargo-deps` line among the leading comments of the script.
+    pub fn find(document: &Document) -> Option<Self> {
+        for line in 0..document.line_count() {
+            let text = document.line(line)?.trim();
+            if line == 0 && document.shebang().is_some() {
+                continue;
+            }
+            if text.is_empty() || text.starts_with(DOC_COMMENT) {
+                continue;
+            }
+            let comment = text.strip_prefix("//")?;
+            if let Some(deps) = comment
+                .trim_start()
+                .strip_prefix("cargo-deps")
+                .and_then(|rest| rest.trim_start().strip_prefix(':'))
+            {
+                return Some(Self {
+                    line,
+                    deps: deps.trim().to_owned(),
+                });
+            }
+        }
+        None
+    }
+
+    /// Converts the dependencies into an equivalent TOML manifest.
+    pub fn to_toml(&self) -> String {
+        let mut toml = "[dependencies]\n".to_owned();
+        for dep in split_top_level(&self.deps, ',') {
+            let dep = dep.trim();
+            if dep.is_empty() {
+                continue;
+            }
+            match split_top_level(dep, '=').as_slice() {
+                [name] => toml.push_str(&format!("{} = \"*\"\n", name.trim())),
+                [name, ..] => {
+                    let spec = dep[name.len() + 1..].trim();
+                    toml.push_str(&format!("{} = {spec}\n", name.trim()));
+                }
+                [] => {}
+            }
+        }
+        toml
+    }
+}
+
+/// Splits `s` by `separator` outside of strings, arrays and inline tables.
+fn split_top_level(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut quote, mut start) = (0, None, 0);
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[' | '{') => depth += 1,
+            (None, ']' | '}') => depth -= 1,
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Returns the lines of the leading `//!` doc comment, as tuples of the line, the byte offset where
+/// the content starts and the content.
+pub fn doc_comment(document: &Document) -> Vec<(u32, usize, &str)> {
+    let mut doc_lines = vec![];
+    for line in 0..document.line_count() {
+        let Some(text) = document.line(line) else {
+            break;
+        };
+        if line == 0 && document.shebang().is_some() {
+            continue;
+        }
+        let trimmed = text.trim_start();
+        match trimmed.strip_prefix(DOC_COMMENT) {
+            Some(content) => {
+                let column = text.len() - content.len();
+                doc_lines.push((line, column, content));
+            }
+            // Allow blank lines and regular comments, e.g. `cargo-deps`, before the doc comment.
+            None if doc_lines.is_empty() && (trimmed.is_empty() || trimmed.starts_with("//")) => {}
+            None => break,
+        }
+    }
+    doc_lines
+}
+
+/// The manifest of a script, whichever form it's written in.
+#[derive(Debug, Clone)]
+pub enum Embedded {
+    Manifest(Manifest),
+    CargoDeps(CargoDeps),
+}
+
+impl Embedded {
+    /// Finds the manifest of the script, preferring the ```` ```cargo ```` block as rust-script does.
+    pub fn find(document: &Document) -> Option<Self> {
+        Manifest::find(document)
+            .map(Self::Manifest)
+            .or_else(|| CargoDeps::find(document).map(Self::CargoDeps))
+    }
+
+    /// Returns the lines of the block including the fences, or the `cargo-deps` line.
+    pub fn lines(&self) -> Range<u32> {
+        match self {
+            Self::Manifest(manifest) => manifest.start_line..manifest.end_line + 1,
+            Self::CargoDeps(cargo_deps) => cargo_deps.line..cargo_deps.line + 1,
+        }
+    }
+
+    /// Returns the manifest as a TOML document.
+    pub fn toml(&self) -> String {
+        match self {
+            Self::Manifest(manifest) => manifest.toml(),
+            Self::CargoDeps(cargo_deps) => cargo_deps.to_toml(),
+        }
+    }
+}
+
+/// Returns the manifest of the script as TOML, whichever form it's written in, along with the lines
+/// to remove to strip it from the script.
+///
+/// The lines include the rest of the doc comment if it becomes empty, or the blank line separating
+/// the manifest from the doc comment otherwise.
+pub fn strip(document: &Document) -> Option<(String, Range<u32>)> {
+    let embedded = Embedded::find(document)?;
+    let (toml, mut removed) = (embedded.toml(), embedded.lines());
+    let doc = doc_comment(document);
+    let remaining = doc
+        .iter()
+        .filter(|(line, _, _)| !removed.contains(line))
+        .collect::<Vec<_>>();
+    if !doc.is_empty()
+        && remaining
+            .iter()
+            .all(|(_, _, content)| content.trim().is_empty())
+    {
+        removed = removed.start.min(doc[0].0)..removed.end.max(doc[doc.len() - 1].0 + 1);
+    } else if remaining
+        .last()
+        .is_some_and(|(line, _, content)| *line + 1 == removed.start && content.trim().is_empty())
+    {
+        removed.start -= 1;
+    }
+    Some((toml, removed))
+}
+
+/// The embedded manifest of a script, i.e. the ```` ```cargo ```` code block in the leading `//!`
+/// doc comment.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    /// The line of the opening fence.
+    pub start_line: u32,
+    /// The line of the closing fence.
+    pub end_line: u32,
+    /// Lines between the fences.
+    pub lines: Vec<ManifestLine>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ManifestLine {
+    pub line: u32,
+    /// Byte offset in the script line where the TOML content starts.
+    pub column: usize,
+    pub text: String,
+}
+
+/// A dependency declared in the manifest.
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub package: String,
+    pub path: Option<String>,
+    pub git: Option<String>,
+    /// Span of the key in [Manifest::toml].
+    pub key_span: Range<usize>,
+    /// Span of the specification in [Manifest::toml].
+    pub value_span: Range<usize>,
+}
+
+impl Manifest {
+    /// Finds the embedded manifest in the leading doc comment of the script.
+    pub fn find(document: &Document) -> Option<Self> {
+        let doc_lines = doc_comment(document);
+        let open = doc_lines
+            .iter()
+            .position(|(_, _, content)| content.trim().starts_with("```cargo"))?;
+        let close = open
+            + 1
+            + doc_lines[open + 1..]
+                .iter()
+                .position(|(_, _, content)| content.trim() == "```")?;
+        let margin = doc_lines[open + 1..close]
+            .iter()
+            .filter(|(_, _, content)| !content.trim().is_empty())
+            .map(|(_, _, content)| content.len() - content.trim_start().len())
+            .min()
+            .unwrap_or(0);
+        let lines = doc_lines[open + 1..close]
+            .iter()
+            .map(|&(line, column, content)| {
+                let skip = margin.min(content.len());
+                ManifestLine {
+                    line,
+                    column: column + skip,
+                    text: content[skip..].to_owned(),
+                }
+            })
+            .collect();
+        Some(Self {
+            start_line: doc_lines[open].0,
+            end_line: doc_lines[close].0,
+            lines,
+        })
+    }
+
+    /// Returns the manifest as a TOML document.
+    pub fn toml(&self) -> String {
+        let mut toml = String::new();
+        for line in &self.lines {
+            toml.push_str(&line.text);
+            toml.push('\n');
+        }
+        toml
+    }
+
+    /// Converts a byte offset in [Self::toml] into the line and the byte offset in the line of the
+    /// script.
+    pub fn script_position(&self, offset: usize) -> Option<(u32, usize)> {
+        let mut start = 0;
+        for line in &self.lines {
+            let end = start + line.text.len();
+            if offset <= end {
+                return Some((line.line, line.column + offset - start));
+            }
+            start = end + 1;
+        }
+        None
+    }
+
+    /// Converts a span in [Self::toml] into a range in the script.
+    pub fn script_range(
+        &self,
+        document: &Document,
+        encoding: Encoding,
+        span: Range<usize>,
+    ) -> Option<lsp_types::Range> {
+        let position = |offset| {
+            let (line, column) = self.script_position(offset)?;
+            let text = document.line(line)?;
+            Some(lsp_types::Position::new(
+                line,
+                encoding.str_len(text.get(..column)?),
+            ))
+        };
+        Some(lsp_types::Range::new(
+            position(span.start)?,
+            position(span.end)?,
+        ))
+    }
+
+    /// Lists dependencies in all the dependency tables, including platform specific ones.
+    pub fn dependencies(&self) -> Vec<Dependency> {
+        let toml = match ImDocument::parse(self.toml()) {
+            Ok(toml) => toml,
+            Err(e) => {
+                tracing::debug!(?e, "failed to parse the manifest");
+                return vec![];
+            }
+        };
+        let mut dependencies = vec![];
+        collect_dependencies(toml.as_table(), &mut dependencies);
+        if let Some(targets) = toml.get("target").and_then(Item::as_table) {
+            for (_, target) in targets.iter() {
+                if let Some(target) = target.as_table() {
+                    collect_dependencies(target, &mut dependencies);
+                }
+            }
+        }
+        dependencies
+    }
+
+    /// Renders TOML text as lines of the doc comment including the fences.
+    pub fn render(toml: &str, line_ending: &str) -> String {
+        let mut rendered = format!("{DOC_COMMENT} ```cargo{line_ending}");
+        for line in toml.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                rendered.push_str(DOC_COMMENT);
+            } else {
+                rendered.push_str(&format!("{DOC_COMMENT} {line}"));
+            }
+            rendered.push_str(line_ending);
+        }
+        rendered.push_str(&format!("{DOC_COMMENT} ```{line_ending}"));
+        rendered
+    }
+}
+
+fn collect_dependencies(table: &Table, dependencies: &mut Vec<Dependency>) {
+    for kind in DEPENDENCY_TABLES {
+        let Some(table) = table.get(kind).and_then(Item::as_table) else {
+            continue;
+        };
+        for (name, item) in table.iter() {
+            let (Some(key_span), Some(value_span)) =
+                (table.key(name).and_then(|key| key.span()), item.span())
+            else {
+                continue;
+            };
+            let field = |field: &str| item.get(field).and_then(Item::as_str).map(str::to_owned);
+            dependencies.push(Dependency {
+                package: field("package").unwrap_or_else(|| name.to_owned()),
+                path: field("path"),
+                git: field("git"),
+                key_span,
+                value_span,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_outside_of_values() {
+        assert_eq!(
+            split_top_level(
+                r#"a="1", b = { version = "2", features = ["x", "y"] }, c"#,
+                ','
+            ),
+            [
+                r#"a="1""#,
+                r#" b = { version = "2", features = ["x", "y"] }"#,
+                " c",
+            ],
+        );
+        assert_eq!(split_top_level(r#"a = "x=y""#, '='), ["a ", r#" "x=y""#]);
+        assert_eq!(split_top_level("'a,b',c", ','), ["'a,b'", "c"]);
+        assert_eq!(split_top_level("", ','), [""]);
+    }
+
+    #[test]
+    fn cargo_deps_to_toml() {
+        let document = Document::new(
+            0,
+            concat!(
+                "#!/usr/bin/env rust-script\n",
+                "// cargo-deps: time=\"0.1\", serde, tokio = { version = \"1\", features = [\"full\"] },\n",
+                "fn main() {}\n",
+            )
+            .to_owned(),
+        );
+        let cargo_deps = CargoDeps::find(&document).unwrap();
+        assert_eq!(cargo_deps.line, 1);
+        assert_eq!(
+            cargo_deps.to_toml(),
+            concat!(
+                "[dependencies]\n",
+                "time = \"0.1\"\n",
+                "serde = \"*\"\n",
+                "tokio = { version = \"1\", features = [\"full\"] }\n",
+            ),
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre, Result, WrapErr as _};
+use lsp_types::{
+    request::{ShowDocument, ShowMessageRequest},
+    MessageActionItem, MessageType, ShowDocumentParams, ShowMessageRequestParams, Url,
+};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::client::ClientHandle;
+
+const DEFAULT_TEMPLATE: &str = r#"#!/usr/bin/env rust-script
+//! ```cargo
+//! [package]
+//! edition = "{{edition}}"
+//!
+//! [dependencies]
+//! ```
+
+fn main() {
+    println!("Hello from {{name}}!");
+}
+"#;
+
+const DEFAULT_EDITION: &str = "2021";
+
+/// Creates an executable script at `path` from the template, and opens it.
+///
+/// Templates are files in `template_dir` named after them, e.g. `tokio.ers` for `tokio`, where
+/// `{{name}}` and `{{edition}}` are replaced. If `template` is not given, the user chooses one of
+/// them.
+pub async fn create(
+    client: &ClientHandle,
+    path: &Path,
+    template: Option<&str>,
+    edition: Option<&str>,
+    template_dir: Option<&Path>,
+) -> Result<Value> {
+    let templates = match template_dir {
+        Some(dir) => list_templates(dir).await,
+        None => vec![],
+    };
+    let template = match template {
+        Some(template) => Some(template.to_owned()),
+        None if templates.is_empty() => None,
+        None => match choose(client, &templates).await? {
+            Some(template) => Some(template),
+            None => return Ok(Value::Null),
+        },
+    };
+    let content = match template.as_deref() {
+        None | Some("default") => DEFAULT_TEMPLATE.to_owned(),
+        Some(template) => {
+            let Some((_, file)) = templates.iter().find(|(name, _)| name == template) else {
+                bail!("no template named `{template}`");
+            };
+            tokio::fs::read_to_string(file)
+                .await
+                .wrap_err_with(|| eyre!("failed to read `{}`", file.display()))?
+        }
+    };
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let content = content
+        .replace("{{name}}", &name)
+        .replace("{{edition}}", edition.unwrap_or(DEFAULT_EDITION));
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .wrap_err_with(|| eyre!("failed to create `{}`", dir.display()))?;
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o755);
+    let mut file = options
+        .open(path)
+        .await
+        .wrap_err_with(|| eyre!("failed to create `{}`", path.display()))?;
+    file.write_all(content.as_bytes())
+        .await
+        .wrap_err_with(|| eyre!("failed to write `{}`", path.display()))?;
+
+    let uri = Url::from_file_path(path).map_err(|()| eyre!("invalid path"))?;
+    let shown = client
+        .request::<ShowDocument>(ShowDocumentParams {
+            uri: uri.clone(),
+            external: None,
+            take_focus: Some(true),
+            selection: None,
+        })
+        .await;
+    if let Err(e) = shown {
+        tracing::warn!(?e, "failed to show the new script");
+    }
+    Ok(json!(uri))
+}
+
+/// Returns the names and the paths of the templates in the directory.
+async fn list_templates(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut templates = vec![];
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        tracing::warn!(?dir, "failed to read the template directory");
+        return templates;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+        {
+            templates.push((name, path));
+        }
+    }
+    templates.sort();
+    templates
+}
+
+async fn choose(client: &ClientHandle, templates: &[(String, PathBuf)]) -> Result<Option<String>> {
+    let actions = std::iter::once("default")
+        .chain(templates.iter().map(|(name, _)| name.as_str()))
+        .map(|name| MessageActionItem {
+            title: name.to_owned(),
+            properties: Default::default(),
+        })
+        .collect();
+    let action = client
+        .request::<ShowMessageRequest>(ShowMessageRequestParams {
+            typ: MessageType::INFO,
+            message: "Choose a template for the new script.".to_owned(),
+            actions: Some(actions),
+        })
+        .await?;
+    Ok(action.map(|action| action.title))
+}
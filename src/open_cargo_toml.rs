@@ -0,0 +1,106 @@
+use eyre::{bail, Result};
+use lsp_types::{
+    request::{ApplyWorkspaceEdit, Request as _, ShowMessageRequest},
+    ApplyWorkspaceEditParams, Location, MessageActionItem, MessageType, Position, Range,
+    ShowMessageRequestParams, TextDocumentContentChangeEvent, TextEdit, Url, WorkspaceEdit,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    client::ClientHandle,
+    document::{Document, Documents, Encoding},
+    lsp_extra::{OpenCargoToml, OpenCargoTomlParams},
+    manifest::{doc_comment, Embedded, Manifest},
+};
+
+const CREATE: &str = "Create manifest";
+
+/// Returns the script if the request is `rust-analyzer/openCargoToml` on a script, which
+/// rust-analyzer would answer with the `Cargo.toml` generated by rust-script.
+pub fn script(request: &lsp_server::Request, documents: &Documents) -> Option<(Url, Document)> {
+    if request.method != OpenCargoToml::METHOD {
+        return None;
+    }
+    let params: OpenCargoTomlParams = serde_json::from_value(request.params.clone()).ok()?;
+    let document = documents.get(&params.text_document.uri)?.clone();
+    Some((params.text_document.uri, document))
+}
+
+/// Answers with the location of the manifest in the script, offering to create one if none.
+pub async fn open(
+    client: ClientHandle,
+    uri: Url,
+    mut document: Document,
+    encoding: Encoding,
+) -> Result<Value> {
+    if let Some(range) = manifest_range(&document, encoding) {
+        return Ok(json!(Location::new(uri, range)));
+    }
+    let action = client
+        .request::<ShowMessageRequest>(ShowMessageRequestParams {
+            typ: MessageType::INFO,
+            message: "The script has no embedded manifest yet.".to_owned(),
+            actions: Some(vec![MessageActionItem {
+                title: CREATE.to_owned(),
+                properties: Default::default(),
+            }]),
+        })
+        .await?;
+    if action.map(|action| action.title).as_deref() != Some(CREATE) {
+        return Ok(Value::Null);
+    }
+    let edit = create_manifest(&document);
+    let response = client
+        .request::<ApplyWorkspaceEdit>(ApplyWorkspaceEditParams {
+            label: Some("Create an embedded manifest".to_owned()),
+            edit: WorkspaceEdit {
+                changes: Some([(uri.clone(), vec![edit.clone()])].into()),
+                ..Default::default()
+            },
+        })
+        .await?;
+    if !response.applied {
+        bail!(
+            "the edit was not applied: {}",
+            response.failure_reason.unwrap_or_default()
+        );
+    }
+    document.apply_change(
+        TextDocumentContentChangeEvent {
+            range: Some(edit.range),
+            range_length: None,
+            text: edit.new_text,
+        },
+        encoding,
+    );
+    Ok(json!(
+        manifest_range(&document, encoding).map(|range| Location::new(uri, range))
+    ))
+}
+
+/// Returns the range of the ```` ```cargo ```` block, or the `cargo-deps` line if none.
+fn manifest_range(document: &Document, encoding: Encoding) -> Option<Range> {
+    let lines = Embedded::find(document)?.lines();
+    let end_line = lines.end - 1;
+    let end = encoding.str_len(document.line(end_line)?);
+    Some(Range::new(
+        Position::new(lines.start, 0),
+        Position::new(end_line, end),
+    ))
+}
+
+/// Creates an edit adding an empty manifest at the end of the doc comment, or after the shebang if
+/// none.
+fn create_manifest(document: &Document) -> TextEdit {
+    let line_ending = document.line_ending();
+    let block = Manifest::render("[dependencies]\n", line_ending);
+    let (line, text) = match doc_comment(document).last() {
+        Some(&(last, _, _)) => (last + 1, format!("//!{line_ending}{block}")),
+        None => {
+            let has_shebang = document.shebang().is_some();
+            (u32::from(has_shebang), block)
+        }
+    };
+    let position = Position::new(line, 0);
+    TextEdit::new(Range::new(position, position), text)
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lsp_types::{
+    notification::Progress as ProgressNotification, request::WorkDoneProgressCreate,
+    NumberOrString, ProgressParams, ProgressParamsValue, ProgressToken, WorkDoneProgress,
+    WorkDoneProgressBegin, WorkDoneProgressCreateParams, WorkDoneProgressEnd,
+    WorkDoneProgressReport,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::client::ClientHandle;
+
+/// A work done progress started by rscls itself.
+///
+/// It silently does nothing if the client doesn't support work done progress, and ends the
+/// progress when dropped.
+pub struct Progress {
+    client: ClientHandle,
+    token: Option<ProgressToken>,
+    cancellation: CancellationToken,
+}
+
+impl Progress {
+    /// Starts a progress without waiting for the client to create it, so that it can be used in
+    /// the main loop as well.
+    pub fn begin(client: &ClientHandle, title: &str, cancellable: bool) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let cancellation = CancellationToken::new();
+        let supported = client
+            .capabilities()
+            .window
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        let mut token = None;
+        if supported {
+            let new_token = NumberOrString::String(format!(
+                "rscls-progress-{}",
+                NEXT_ID.fetch_add(1, Ordering::SeqCst)
+            ));
+            let created = client.request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                token: new_token.clone(),
+            });
+            tokio::spawn(async move {
+                if let Err(e) = created.await {
+                    tracing::warn!(?e, "failed to create a progress");
+                }
+            });
+            client.register_progress(new_token.clone(), cancellation.clone());
+            token = Some(new_token);
+        }
+        let progress = Self {
+            client: client.clone(),
+            token,
+            cancellation,
+        };
+        progress.notify(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: title.to_owned(),
+            cancellable: Some(cancellable),
+            message: None,
+            percentage: None,
+        }));
+        progress
+    }
+
+    pub fn report(&self, message: impl Into<String>) {
+        self.notify(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: None,
+            message: Some(message.into()),
+            percentage: None,
+        }));
+    }
+
+    /// Resolves when the user cancelled the progress.
+    pub async fn cancelled(&self) {
+        self.cancellation.cancelled().await
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    pub fn end(mut self, message: impl Into<String>) {
+        self.finish(Some(message.into()));
+    }
+
+    fn finish(&mut self, message: Option<String>) {
+        if let Some(token) = &self.token {
+            self.notify(WorkDoneProgress::End(WorkDoneProgressEnd { message }));
+            self.client.unregister_progress(token);
+            self.token = None;
+        }
+    }
+
+    fn notify(&self, progress: WorkDoneProgress) {
+        if let Some(token) = &self.token {
+            self.client.notify::<ProgressNotification>(ProgressParams {
+                token: token.clone(),
+                value: ProgressParamsValue::WorkDone(progress),
+            });
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result, WrapErr as _};
+use lsp_types::{
+    notification::ShowMessage, request::RegisterCapability, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, FileSystemWatcher, GlobPattern, MessageType,
+    Registration, RegistrationParams, ShowMessageParams,
+};
+use serde_json::{json, Value};
+use toml_edit::{DocumentMut, Item};
+
+use crate::{
+    client::ClientHandle,
+    settings::{self, NEED_TRUST},
+};
+
+pub const FILE_NAME: &str = "rscls.toml";
+
+/// Settings of programs, which are resolved against the directory of the file if they're paths
+/// rather than names.
+const PROGRAMS: [&str; 3] = ["rustScript", "rustAnalyzer", "rustc"];
+/// Settings of directories, which are resolved against the directory of the file.
+const DIRECTORIES: [&str; 2] = ["templateDir", "targetDir"];
+
+/// Reads the settings in the directory, from `rscls.toml`, or `[workspace.metadata.rscls]` of
+/// `Cargo.toml` if none.
+pub async fn read(dir: &Path) -> Result<Option<Value>> {
+    let path = dir.join(FILE_NAME);
+    if let Ok(text) = tokio::fs::read_to_string(&path).await {
+        let toml = text
+            .parse::<DocumentMut>()
+            .wrap_err_with(|| eyre!("failed to parse `{}`", path.display()))?;
+        return Ok(Some(resolve_paths(to_json(toml.as_item()), dir)));
+    }
+    // A broken `Cargo.toml` is for rust-analyzer to complain about.
+    let Ok(Ok(toml)) = tokio::fs::read_to_string(dir.join("Cargo.toml"))
+        .await
+        .map(|text| text.parse::<DocumentMut>())
+    else {
+        return Ok(None);
+    };
+    Ok(toml
+        .get("workspace")
+        .and_then(|workspace| workspace.get("metadata"))
+        .and_then(|metadata| metadata.get(settings::SECTION))
+        .map(|item| resolve_paths(to_json(item), dir)))
+}
+
+/// Reads project files in the directories and their ancestors, and returns ones different from
+/// the known ones in `layers`, where `None` means it's gone.
+pub async fn load(
+    client: &ClientHandle,
+    dirs: impl IntoIterator<Item = PathBuf>,
+    layers: &settings::Layers,
+) -> Vec<(PathBuf, Option<Value>)> {
+    let trust = layers.trust();
+    let mut dirs = dirs
+        .into_iter()
+        .flat_map(|dir| dir.ancestors().map(Path::to_path_buf).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    let mut changes = vec![];
+    for dir in dirs {
+        let file = match read(&dir).await {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!(?e, "ignoring invalid project file");
+                client.notify::<ShowMessage>(ShowMessageParams {
+                    typ: MessageType::WARNING,
+                    message: format!("{e:#}"),
+                });
+                None
+            }
+        };
+        if layers.files.get(&dir) == file.as_ref() {
+            continue;
+        }
+        if let (false, Some(file)) = (trust.is_trusted(&dir), &file) {
+            let ignored = NEED_TRUST
+                .into_iter()
+                .filter(|key| file.get(key).is_some())
+                .map(|key| format!("`{key}`"))
+                .collect::<Vec<_>>();
+            if !ignored.is_empty() {
+                client.notify::<ShowMessage>(ShowMessageParams {
+                    typ: MessageType::WARNING,
+                    message: format!(
+                        "Ignoring {} in the settings of `{}`, add the directory to `trustedDirs` to apply them",
+                        ignored.join(", "),
+                        dir.display(),
+                    ),
+                });
+            }
+        }
+        changes.push((dir, file));
+    }
+    changes
+}
+
+/// Returns directories whose project file may have changed.
+pub fn changed_dirs(params: &DidChangeWatchedFilesParams) -> Vec<PathBuf> {
+    params
+        .changes
+        .iter()
+        .filter_map(|change| change.uri.to_file_path().ok())
+        .filter(|path| path.ends_with(FILE_NAME) || path.ends_with("Cargo.toml"))
+        .filter_map(|path| Some(path.parent()?.to_path_buf()))
+        .collect()
+}
+
+/// Asks the client to notify changes of project files, if it can.
+pub async fn watch(client: ClientHandle) {
+    let supported = client
+        .capabilities()
+        .workspace
+        .and_then(|workspace| workspace.did_change_watched_files)
+        .and_then(|watched_files| watched_files.dynamic_registration)
+        .unwrap_or(false);
+    if !supported {
+        return;
+    }
+    let options = DidChangeWatchedFilesRegistrationOptions {
+        // Manifests of projects are watched for discovering them as well.
+        watchers: [
+            format!("**/{FILE_NAME}"),
+            "**/Cargo.toml".to_owned(),
+            "**/rust-project.json".to_owned(),
+        ]
+        .map(|glob| FileSystemWatcher {
+            glob_pattern: GlobPattern::String(glob),
+            kind: None,
+        })
+        .into(),
+    };
+    let registered = client
+        .request::<RegisterCapability>(RegistrationParams {
+            registrations: vec![Registration {
+                id: "rscls-project-files".to_owned(),
+                method: "workspace/didChangeWatchedFiles".to_owned(),
+                register_options: Some(json!(options)),
+            }],
+        })
+        .await;
+    if let Err(e) = registered {
+        tracing::warn!(?e, "failed to watch project files");
+    }
+}
+
+fn resolve_paths(mut value: Value, dir: &Path) -> Value {
+    let Some(object) = value.as_object_mut() else {
+        return value;
+    };
+    for (key, value) in object.iter_mut() {
+        let Some(path) = value.as_str().map(Path::new) else {
+            continue;
+        };
+        // Programs without a directory are looked up in `PATH`.
+        let is_path = DIRECTORIES.contains(&key.as_str())
+            || PROGRAMS.contains(&key.as_str()) && path.components().count() > 1;
+        if is_path && path.is_relative() {
+            *value = json!(dir.join(path));
+        }
+    }
+    value
+}
+
+fn to_json(item: &Item) -> Value {
+    if let Some(table) = item.as_table_like() {
+        return Value::Object(
+            table
+                .iter()
+                .map(|(key, item)| (key.to_owned(), to_json(item)))
+                .collect(),
+        );
+    }
+    if let Some(array) = item.as_array_of_tables() {
+        return array
+            .iter()
+            .map(|table| to_json(&Item::Table(table.clone())))
+            .collect();
+    }
+    match item.as_value() {
+        Some(toml_edit::Value::String(value)) => json!(value.value()),
+        Some(toml_edit::Value::Integer(value)) => json!(value.value()),
+        Some(toml_edit::Value::Float(value)) => json!(value.value()),
+        Some(toml_edit::Value::Boolean(value)) => json!(value.value()),
+        Some(toml_edit::Value::Datetime(value)) => json!(value.value().to_string()),
+        Some(toml_edit::Value::Array(array)) => array
+            .iter()
+            .map(|value| to_json(&Item::Value(value.clone())))
+            .collect(),
+        // Inline tables are table-like.
+        Some(toml_edit::Value::InlineTable(_)) | None => Value::Null,
+    }
+}
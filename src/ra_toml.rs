@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use eyre::{eyre, Result, WrapErr as _};
+use serde_json::Value;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table};
+
+pub const FILE_NAME: &str = "rust-analyzer.toml";
+
+/// Marks the file as ours, so that we don't remove one we didn't write.
+const HEADER: &str = "# Generated by rscls from the settings of rust-analyzer for scripts.\n";
+
+/// Writes the settings into `rust-analyzer.toml` of the package generated for a script, which
+/// rust-analyzer reads as the settings of the project, or removes the one we wrote if none.
+pub async fn write(package_dir: &Path, settings: &Value) -> Result<()> {
+    let path = package_dir.join(FILE_NAME);
+    let existing = tokio::fs::read_to_string(&path).await.ok();
+    let ours = existing
+        .as_deref()
+        .is_none_or(|existing| existing.starts_with(HEADER));
+    if !ours {
+        tracing::warn!(
+            ?path,
+            "not overwriting rust-analyzer.toml rscls didn't write"
+        );
+        return Ok(());
+    }
+    let toml = match settings {
+        Value::Object(settings) if !settings.is_empty() => {
+            let mut document = DocumentMut::new();
+            for (key, value) in settings {
+                if let Some(item) = to_item(value) {
+                    document.insert(key, item);
+                }
+            }
+            Some(format!("{HEADER}{document}"))
+        }
+        _ => None,
+    };
+    match toml {
+        Some(toml) if existing.as_ref() != Some(&toml) => tokio::fs::write(&path, toml)
+            .await
+            .wrap_err_with(|| eyre!("failed to write `{}`", path.display())),
+        Some(_) => Ok(()),
+        None if existing.is_some() => tokio::fs::remove_file(&path)
+            .await
+            .wrap_err_with(|| eyre!("failed to remove `{}`", path.display())),
+        None => Ok(()),
+    }
+}
+
+fn to_item(value: &Value) -> Option<Item> {
+    match value {
+        Value::Object(object) => {
+            let mut table = Table::new();
+            // Only show headers of tables with values.
+            table.set_implicit(true);
+            for (key, value) in object {
+                if let Some(item) = to_item(value) {
+                    table.insert(key, item);
+                }
+            }
+            Some(Item::Table(table))
+        }
+        value => to_value(value).map(Item::Value),
+    }
+}
+
+fn to_value(value: &Value) -> Option<toml_edit::Value> {
+    Some(match value {
+        Value::Null => return None,
+        Value::Bool(value) => (*value).into(),
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => integer.into(),
+            None => number.as_f64()?.into(),
+        },
+        Value::String(value) => value.into(),
+        Value::Array(values) => values.iter().filter_map(to_value).collect::<Array>().into(),
+        Value::Object(object) => object
+            .iter()
+            .filter_map(|(key, value)| Some((key, to_value(value)?)))
+            .collect::<InlineTable>()
+            .into(),
+    })
+}
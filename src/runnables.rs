@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use lsp_types::{CodeLens, Command, Url};
+use serde_json::{json, Value};
+
+use crate::{commands, settings::Settings};
+
+/// Rewrites a cargo runnable rust-analyzer offers for the generated package of the script into a
+/// rust-script invocation on the script itself, run from the directory of the script.
+///
+/// `run`, `test` and `bench` become `rust-script <script>`, `rust-script --test <script>` and
+/// `rust-script --bench <script>` respectively, and the others are left as is. The profile of
+/// rust-script applies as well, with its wrapper as the program to run.
+pub fn rewrite(runnable: &mut Value, settings: &Settings, script: &Path) {
+    let script_arg = script.to_string_lossy().into_owned();
+    let mode_args = match subcommand(runnable) {
+        Some("run") => vec![script_arg],
+        Some("test") => vec!["--test".to_owned(), script_arg],
+        Some("bench") => vec!["--bench".to_owned(), script_arg],
+        _ => return,
+    };
+    let Some(args) = runnable.get_mut("args").and_then(Value::as_object_mut) else {
+        return;
+    };
+    let profile = &settings.profiles.rust_script;
+    let rust_script = settings.rust_script.to_string_lossy().into_owned();
+    let mut command = profile.wrapper.clone();
+    command.push(rust_script);
+    command.extend(profile.args.iter().cloned());
+    command.extend(mode_args);
+    let program = command.remove(0);
+    args.insert("cargoArgs".to_owned(), json!(command));
+    args.insert("cargoExtraArgs".to_owned(), json!([]));
+    args.insert("overrideCargo".to_owned(), json!(program));
+    let mut env = profile.env.clone();
+    if let Some(target_dir) = &settings.target_dir {
+        env.insert(
+            "CARGO_TARGET_DIR".to_owned(),
+            target_dir.to_string_lossy().into_owned(),
+        );
+    }
+    if !env.is_empty() {
+        let environment = args
+            .entry("environment")
+            .or_insert_with(|| json!({}))
+            .as_object_mut();
+        if let Some(environment) = environment {
+            environment.extend(env.into_iter().map(|(key, value)| (key, json!(value))));
+        }
+    }
+    if let Some(dir) = script.parent() {
+        args.insert("workspaceRoot".to_owned(), json!(dir));
+        args.insert("cwd".to_owned(), json!(dir));
+    }
+}
+
+/// Maps the run and debug lenses rust-analyzer offers on the script to rust-script and
+/// [commands::BUILD_FOR_DEBUG] respectively.
+pub fn rewrite_lens(lens: &mut CodeLens, uri: &Url, settings: &Settings, script: &Path) {
+    let Some(command) = &mut lens.command else {
+        return;
+    };
+    let Some(runnable) = command
+        .arguments
+        .as_mut()
+        .and_then(|arguments| arguments.first_mut())
+    else {
+        return;
+    };
+    match command.command.as_str() {
+        "rust-analyzer.runSingle" => rewrite(runnable, settings, script),
+        "rust-analyzer.debugSingle" => {
+            let Some(mode) = subcommand(runnable).map(str::to_owned) else {
+                return;
+            };
+            let args = match &runnable["args"]["executableArgs"] {
+                Value::Null => json!([]),
+                args => args.clone(),
+            };
+            *command = Command::new(
+                command.title.clone(),
+                commands::BUILD_FOR_DEBUG.to_owned(),
+                Some(vec![json!(uri), args, json!(mode)]),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Returns the cargo subcommand of the runnable, if it's a cargo one.
+fn subcommand(runnable: &Value) -> Option<&str> {
+    if runnable.get("kind").and_then(Value::as_str) != Some("cargo") {
+        return None;
+    }
+    runnable["args"]["cargoArgs"].get(0).and_then(Value::as_str)
+}
@@ -0,0 +1,402 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use eyre::{bail, eyre, Result, WrapErr as _};
+use lsp_types::{
+    notification::{LogMessage, ShowMessage},
+    LogMessageParams, MessageType, ShowMessageParams,
+};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead, BufReader},
+    process::Command,
+};
+use toml_edit::DocumentMut;
+
+use crate::{
+    client::ClientHandle,
+    document::Document,
+    eject,
+    manifest::{self, Manifest},
+    progress::Progress,
+    script,
+    settings::Settings,
+};
+
+/// How long evaluating an expression may take, including building the dependencies.
+const EVALUATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Runs the script with rust-script, streaming its output to the client.
+pub async fn run_script(
+    client: &ClientHandle,
+    settings: &Settings,
+    script: &Path,
+    args: &[String],
+) -> Result<Value> {
+    let name = script.file_name().map_or_else(
+        || script.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let mut command = settings.rust_script_command();
+    command.arg(script).args(args);
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    let progress = Progress::begin(client, &format!("Running {name}"), true);
+    let output = run_streaming(client, command, &progress, None, true).await?;
+    let message = match output.status {
+        Some(0) => format!("{name} finished in {:.2?}", output.duration),
+        Some(status) => format!(
+            "{name} exited with status {status} in {:.2?}",
+            output.duration
+        ),
+        None if output.cancelled => format!("{name} was cancelled"),
+        None => format!("{name} was terminated by a signal"),
+    };
+    client.notify::<ShowMessage>(ShowMessageParams {
+        typ: if output.status == Some(0) {
+            MessageType::INFO
+        } else {
+            MessageType::ERROR
+        },
+        message: message.clone(),
+    });
+    progress.end(message);
+    Ok(json!({
+        "exitCode": output.status,
+        "durationMs": output.duration.as_millis() as u64,
+    }))
+}
+
+/// Evaluates the expression with rust-script, through a temporary script with the manifest of the
+/// original one, and shows the result to the client.
+pub async fn evaluate(
+    client: &ClientHandle,
+    settings: &Settings,
+    script: &Path,
+    document: &Document,
+    expression: &str,
+) -> Result<Value> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let dir = script
+        .parent()
+        .ok_or_else(|| eyre!("`{}` has no parent directory", script.display()))?;
+    let stem = script
+        .file_stem()
+        .map_or_else(|| "script".into(), |stem| stem.to_string_lossy());
+    // Out of the workspace, so that neither watchers nor script discovery see it.
+    let evaluation_dir = std::env::temp_dir().join(format!(
+        "rscls-evaluate-{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let evaluation = evaluation_dir.join(format!("{stem}.ers"));
+    let text = evaluation_script(document, expression, dir, &evaluation_dir)?;
+    tokio::fs::create_dir_all(&evaluation_dir)
+        .await
+        .wrap_err_with(|| eyre!("failed to create `{}`", evaluation_dir.display()))?;
+    // Remove the directory whether it fails or not.
+    let result = async {
+        tokio::fs::write(&evaluation, text)
+            .await
+            .wrap_err_with(|| eyre!("failed to write `{}`", evaluation.display()))?;
+        evaluate_script(client, settings, &evaluation, dir).await
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_dir_all(&evaluation_dir).await {
+        tracing::warn!(
+            ?evaluation_dir,
+            ?e,
+            "failed to remove the script for evaluation"
+        );
+    }
+    result
+}
+
+async fn evaluate_script(
+    client: &ClientHandle,
+    settings: &Settings,
+    evaluation: &Path,
+    dir: &Path,
+) -> Result<Value> {
+    let mut command = settings.rust_script_command();
+    command.arg(evaluation).current_dir(dir);
+    let progress = Progress::begin(client, "Evaluating the selection", true);
+    let output = run_streaming(client, command, &progress, Some(EVALUATION_TIMEOUT), true).await?;
+    let (typ, message) = match output.status {
+        Some(0) => (MessageType::INFO, output.stdout.trim_end().to_owned()),
+        Some(status) => (
+            MessageType::ERROR,
+            format!("Evaluation failed with status {status}, see the log for details"),
+        ),
+        None if output.cancelled => (MessageType::INFO, "Evaluation was cancelled".to_owned()),
+        None if output.timed_out => (
+            MessageType::ERROR,
+            format!("Evaluation timed out after {EVALUATION_TIMEOUT:?}"),
+        ),
+        None => (
+            MessageType::ERROR,
+            "Evaluation was terminated by a signal".to_owned(),
+        ),
+    };
+    client.notify::<ShowMessage>(ShowMessageParams {
+        typ,
+        message: message.clone(),
+    });
+    progress.end(message);
+    Ok(json!({
+        "result": (output.status == Some(0)).then(|| output.stdout.trim_end().to_owned()),
+        "exitCode": output.status,
+        "durationMs": output.duration.as_millis() as u64,
+    }))
+}
+
+/// Creates a script in `dir` with the manifest of the script in `script_dir`, printing the
+/// expression.
+fn evaluation_script(
+    document: &Document,
+    expression: &str,
+    script_dir: &Path,
+    dir: &Path,
+) -> Result<String> {
+    let mut text = String::new();
+    if let Some((toml, _)) = manifest::strip(document) {
+        let mut toml = toml
+            .parse::<DocumentMut>()
+            .wrap_err("the embedded manifest is invalid")?;
+        eject::rebase_paths(toml.as_table_mut(), script_dir, dir);
+        text.push_str(&Manifest::render(&toml.to_string(), "\n"));
+    }
+    text.push_str(&format!(
+        "fn main() {{\n    println!(\"{{:?}}\", {{\n{expression}\n    }});\n}}\n"
+    ));
+    Ok(text)
+}
+
+/// What to build for debugging, corresponding to `run`, `test` and `bench` runnables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildMode {
+    Run,
+    Test,
+    Bench,
+}
+impl BuildMode {
+    pub fn from_subcommand(subcommand: &str) -> Option<Self> {
+        match subcommand {
+            "run" => Some(Self::Run),
+            "test" => Some(Self::Test),
+            "bench" => Some(Self::Bench),
+            _ => None,
+        }
+    }
+}
+
+/// Builds the package generated for the script with cargo, and returns how to launch the
+/// executable in the shape of launch configurations of debug adapters, i.e. `program`, `args` and
+/// `cwd`.
+pub async fn build_for_debug(
+    client: &ClientHandle,
+    settings: &Settings,
+    script: &Path,
+    args: &[String],
+    mode: BuildMode,
+) -> Result<Value> {
+    let name = script.file_name().map_or_else(
+        || script.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let package_dir = script::package_dir(settings, script).await?;
+    let mut command = settings.cargo_command();
+    match mode {
+        // The default profile has debug info.
+        BuildMode::Run => command.arg("build"),
+        BuildMode::Test => command.args(["test", "--no-run"]),
+        BuildMode::Bench => command.args(["bench", "--no-run"]),
+    };
+    command
+        .arg("--message-format=json-render-diagnostics")
+        .current_dir(&package_dir);
+    let progress = Progress::begin(client, &format!("Building {name} for debugging"), true);
+    let output = run_streaming(client, command, &progress, None, false).await?;
+    if output.status != Some(0) {
+        progress.end(format!("Failed to build {name}"));
+        bail!(
+            "failed to build `{}`, see the log for details",
+            script.display()
+        );
+    }
+    let program = output
+        .stdout
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact"
+                && message["profile"]["test"].as_bool() == Some(mode != BuildMode::Run)
+        })
+        .filter_map(|message| message["executable"].as_str().map(PathBuf::from))
+        .next()
+        .ok_or_else(|| {
+            eyre!(
+                "cargo didn't report any executable for `{}`",
+                script.display()
+            )
+        })?;
+    let message = format!("Built {} for debugging", program.display());
+    client.notify::<ShowMessage>(ShowMessageParams {
+        typ: MessageType::INFO,
+        message: message.clone(),
+    });
+    progress.end(message);
+    Ok(json!({
+        "program": program,
+        "args": args,
+        "cwd": script.parent(),
+        "sourceLanguages": ["rust"],
+    }))
+}
+
+pub struct Output {
+    /// The exit code, which is `None` if terminated by a signal, cancelled or timed out.
+    pub status: Option<i32>,
+    pub cancelled: bool,
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: String,
+}
+
+/// Runs the command, logging each line of its output to the client and reporting it to the
+/// progress, until it exits, the user cancels the progress or it runs out of `timeout`.
+///
+/// The stdout is always collected, and logged only if `log_stdout`, i.e. unless it's for machines.
+pub async fn run_streaming(
+    client: &ClientHandle,
+    mut command: Command,
+    progress: &Progress,
+    timeout: Option<Duration>,
+    log_stdout: bool,
+) -> Result<Output> {
+    let start = Instant::now();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .wrap_err_with(|| eyre!("failed to run `{command:?}`"))?;
+    let stdout = forward_lines(
+        client,
+        progress,
+        child.stdout.take(),
+        log_stdout.then_some(MessageType::LOG),
+    );
+    let stderr = forward_lines(
+        client,
+        progress,
+        child.stderr.take(),
+        Some(MessageType::WARNING),
+    );
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let mut output = Output {
+        status: None,
+        cancelled: false,
+        timed_out: false,
+        duration: Duration::ZERO,
+        stdout: String::new(),
+    };
+    tokio::select! {
+        (status, stdout, _) = futures::future::join3(child.wait(), stdout, stderr) => {
+            output.status = status.wrap_err("failed to wait for the command")?.code();
+            output.stdout = stdout;
+        }
+        _ = progress.cancelled() => {
+            child.kill().await.ok();
+            output.cancelled = true;
+        }
+        _ = deadline => {
+            child.kill().await.ok();
+            output.timed_out = true;
+        }
+    };
+    output.duration = start.elapsed();
+    Ok(output)
+}
+
+/// Forwards the output line by line if `typ` is given, and returns the whole of it.
+async fn forward_lines(
+    client: &ClientHandle,
+    progress: &Progress,
+    output: Option<impl AsyncRead + Unpin>,
+    typ: Option<MessageType>,
+) -> String {
+    let mut collected = String::new();
+    let Some(output) = output else {
+        return collected;
+    };
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        collected.push_str(&line);
+        collected.push('\n');
+        if let Some(typ) = typ {
+            progress.report(line.clone());
+            client.notify::<LogMessage>(LogMessageParams { typ, message: line });
+        }
+    }
+    collected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluation_script_keeps_manifest() {
+        let document = Document::new(
+            0,
+            concat!(
+                "#!/usr/bin/env rust-script\n",
+                "//! ```cargo\n",
+                "//! [dependencies]\n",
+                "//! serde = { version = \"1\", features = [\"derive\"] }\n",
+                "//! foo = { path = \"../foo\" }\n",
+                "//! ```\n",
+                "fn main() {}\n",
+            )
+            .to_owned(),
+        );
+        assert_eq!(
+            evaluation_script(
+                &document,
+                "1 + 1",
+                Path::new("/work/scripts"),
+                Path::new("/tmp/rscls-evaluate-0"),
+            )
+            .unwrap(),
+            concat!(
+                "//! ```cargo\n",
+                "//! [dependencies]\n",
+                "//! serde = { version = \"1\", features = [\"derive\"] }\n",
+                "//! foo = { path = \"../../work/foo\" }\n",
+                "//! ```\n",
+                "fn main() {\n",
+                "    println!(\"{:?}\", {\n",
+                "1 + 1\n",
+                "    });\n",
+                "}\n",
+            ),
+        );
+
+        let document = Document::new(0, "// cargo-deps: time=\"0.1\"\nfn main() {}\n".to_owned());
+        let text = evaluation_script(&document, "1", Path::new("/"), Path::new("/tmp")).unwrap();
+        assert!(text.starts_with("//! ```cargo\n//! [dependencies]\n//! time = \"0.1\"\n"));
+    }
+}
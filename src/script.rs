@@ -1,53 +1,115 @@
 use std::{
     collections::BTreeMap,
     path::{Path, PathBuf},
+    process::Stdio,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, RwLock,
     },
 };
 
-use eyre::{ensure, eyre, Result, WrapErr as _};
+use eyre::{bail, ensure, eyre, Result, WrapErr as _};
 use futures::future::JoinAll;
+use lsp_types::{
+    notification::LogMessage,
+    request::{ShowDocument, ShowMessageRequest},
+    LogMessageParams, MessageActionItem, MessageType, ShowDocumentParams, ShowMessageRequestParams,
+};
 use path_absolutize::Absolutize as _;
 use serde_json::{json, Value};
 use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, BufReader},
     process::Command,
     spawn,
-    sync::{Mutex, OnceCell},
+    sync::Mutex,
 };
 
-use crate::event::EventSender;
+use crate::{
+    client::ClientHandle,
+    detection,
+    directive::Directives,
+    event::{Event, EventSender},
+    progress::Progress,
+    ra_toml,
+    settings::{self, Backend, Refresh, Settings},
+};
 
 struct Script {
+    client: ClientHandle,
+    event_sender: EventSender,
+    uri: lsp_types::Url,
     source: PathBuf,
-    rust_script: Arc<PathBuf>,
-    fallback_project: Value,
+    settings: settings::Shared,
     project: RwLock<Arc<Option<PathBuf>>>,
     need_refresh: AtomicBool,
     refresh_lock: tokio::sync::Mutex<()>,
+    /// The error of the last refresh, to not bother the user with the same one repeatedly.
+    last_error: std::sync::Mutex<Option<String>>,
+    /// Settings of rust-analyzer the client gives for the script in particular.
+    scoped_settings: std::sync::Mutex<Value>,
+    directives: std::sync::Mutex<Directives>,
+    log_file: Option<Arc<PathBuf>>,
 }
 impl Script {
-    async fn new(source: PathBuf, rustc: &PathBuf, rust_script: Arc<PathBuf>) -> Self {
-        let fallback_project = create_default_project(&source, rustc).await;
+    fn new(scripts: &Scripts, uri: lsp_types::Url, source: PathBuf) -> Self {
         Self {
+            client: scripts.client.clone(),
+            event_sender: scripts.event_sender.clone(),
+            uri,
             source,
-            rust_script,
-            fallback_project,
+            settings: scripts.settings.clone(),
             project: RwLock::new(Arc::new(None)),
             need_refresh: AtomicBool::new(false),
             refresh_lock: Mutex::new(()),
+            last_error: Default::default(),
+            scoped_settings: Default::default(),
+            directives: Default::default(),
+            log_file: scripts.log_file.clone(),
         }
     }
 
     async fn project(&self) -> Value {
+        let settings = self.settings.for_path(&self.source);
         let tmp = self.project.read().unwrap().clone();
-        if let Some(manifest) = tmp.as_ref() {
+        if let (Backend::RustScript, Some(manifest)) = (settings.backend, tmp.as_ref()) {
             if tokio::fs::metadata(manifest).await.is_ok() {
                 return serde_json::to_value(manifest).unwrap();
             }
         }
-        self.fallback_project.clone()
+        let directives = self.directives.lock().unwrap().clone();
+        create_default_project(&self.source, &settings, &directives).await
+    }
+
+    /// Parses the directives of the script again, and returns whether they changed.
+    async fn load_directives(&self) -> bool {
+        let directives = match tokio::fs::read_to_string(&self.source).await {
+            Ok(text) => Directives::parse(&text),
+            Err(e) => {
+                tracing::warn!(script = ?self.source, ?e, "failed to read directives");
+                return false;
+            }
+        };
+        let mut current = self.directives.lock().unwrap();
+        if *current == directives {
+            return false;
+        }
+        *current = directives;
+        true
+    }
+
+    /// Returns the settings of rust-analyzer only for the script.
+    fn rust_analyzer_settings(&self, settings: &Settings) -> Value {
+        let mut value = settings.script_rust_analyzer_settings.clone();
+        settings::merge(&mut value, &self.scoped_settings.lock().unwrap());
+        settings::merge(
+            &mut value,
+            &self.directives.lock().unwrap().rust_analyzer_settings(),
+        );
+        value
+    }
+
+    fn manifest_path(&self) -> Option<PathBuf> {
+        self.project.read().unwrap().as_ref().clone()
     }
 
     async fn queue_refresh(self: &Arc<Self>, refreshed: impl FnOnce() + Send + 'static) {
@@ -61,111 +123,369 @@ impl Script {
         if !self.need_refresh.swap(false, Ordering::SeqCst) {
             return;
         }
-        let project_dir = match package_dir(self.rust_script.as_ref(), self.source.as_path()).await
+        let settings = self.settings.for_path(&self.source);
+        // The fallback project has them as well.
+        let directives_changed = self.load_directives().await;
+        if settings.backend != Backend::RustScript {
+            if directives_changed {
+                refreshed();
+            }
+            return;
+        }
+        let name = self.source.file_name().map_or_else(
+            || self.source.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let progress = Progress::begin(&self.client, &format!("Loading {name}"), true);
+        let project_dir = match package_dir_with_progress(
+            &settings,
+            self.source.as_path(),
+            &progress,
+        )
+        .await
         {
-            Ok(project_dir) => project_dir,
+            Ok(project_dir) => {
+                progress.end("Loaded");
+                self.report(None);
+                project_dir
+            }
+            Err(_) if progress.is_cancelled() => {
+                tracing::info!(script = ?self.source, "loading script was cancelled");
+                progress.end("Cancelled");
+                return;
+            }
             Err(e) => {
                 tracing::error!(script = ?self.source, ?e, "failed to load script as a project");
+                progress.end(format!("Failed: {e}"));
+                self.report(Some(format!("{e:#}")));
+                if directives_changed {
+                    refreshed();
+                }
                 return;
             }
         };
+        let ra_settings = self.rust_analyzer_settings(&settings);
+        if let Err(e) = ra_toml::write(&project_dir, &ra_settings).await {
+            tracing::warn!(script = ?self.source, ?e, "failed to write settings for the script");
+        }
         let new_project = Some(project_dir.join("Cargo.toml"));
         let mut project_write = self.project.write().unwrap();
         if project_write.as_ref() != &new_project {
             *project_write = new_project.into();
             tracing::info!(script = ?self.source, "reloaded project");
             refreshed();
+        } else if directives_changed {
+            tracing::info!(script = ?self.source, "directives changed");
+            refreshed();
         } else {
             tracing::info!(script = ?self.source, "no project diff found");
         }
     }
+
+    /// Surfaces the failure to load the script to the user, unless it's the same as the last one.
+    fn report(self: &Arc<Self>, error: Option<String>) {
+        let mut last_error = self.last_error.lock().unwrap();
+        if *last_error == error {
+            return;
+        }
+        *last_error = error.clone();
+        self.event_sender
+            .send(Event::Loaded {
+                script: self.uri.clone(),
+                error: error.clone(),
+            })
+            .ok();
+        if let Some(error) = error {
+            spawn(self.clone().prompt(error));
+        }
+    }
+
+    async fn prompt(self: Arc<Self>, error: String) {
+        const SHOW_LOG: &str = "Show log";
+        const RETRY: &str = "Retry";
+        const USE_FALLBACK: &str = "Use fallback";
+        let name = self.source.file_name().map_or_else(
+            || self.source.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let action = self
+            .client
+            .request::<ShowMessageRequest>(ShowMessageRequestParams {
+                typ: MessageType::ERROR,
+                message: format!(
+                    "rust-script failed to load {name}, so its dependencies are not available."
+                ),
+                actions: Some(
+                    [SHOW_LOG, RETRY, USE_FALLBACK]
+                        .map(|title| MessageActionItem {
+                            title: title.to_owned(),
+                            properties: Default::default(),
+                        })
+                        .into(),
+                ),
+            })
+            .await;
+        let action = match action {
+            Ok(action) => action.map(|action| action.title),
+            Err(e) => {
+                tracing::warn!(?e, "failed to ask what to do with the failure");
+                return;
+            }
+        };
+        match action.as_deref() {
+            Some(SHOW_LOG) => {
+                self.client.notify::<LogMessage>(LogMessageParams {
+                    typ: MessageType::ERROR,
+                    message: error,
+                });
+                let Some(uri) = self
+                    .log_file
+                    .as_ref()
+                    .and_then(|log_file| lsp_types::Url::from_file_path(log_file.as_ref()).ok())
+                else {
+                    return;
+                };
+                let shown = self
+                    .client
+                    .request::<ShowDocument>(ShowDocumentParams {
+                        uri,
+                        external: None,
+                        take_focus: Some(true),
+                        selection: None,
+                    })
+                    .await;
+                if let Err(e) = shown {
+                    tracing::warn!(?e, "failed to show the log");
+                }
+            }
+            Some(RETRY) => {
+                // Let the user know if it fails again.
+                *self.last_error.lock().unwrap() = None;
+                let sender = self.event_sender.clone();
+                self.queue_refresh(move || sender.mark_need_reload()).await;
+            }
+            Some(USE_FALLBACK) => {
+                *self.project.write().unwrap() = Arc::new(None);
+                self.event_sender.mark_need_reload();
+            }
+            _ => {}
+        }
+    }
 }
 
 pub struct Scripts {
+    client: ClientHandle,
     event_sender: EventSender,
-    rustc: PathBuf,
-    rust_script: Arc<PathBuf>,
+    settings: settings::Shared,
+    log_file: Option<Arc<PathBuf>>,
     scripts: BTreeMap<lsp_types::Url, Arc<Script>>,
+    /// Cargo projects scripts were ejected into, linked until the scripts are closed.
+    ejected: BTreeMap<lsp_types::Url, PathBuf>,
 }
 impl Scripts {
-    pub fn new(event_sender: EventSender, rustc: PathBuf, rust_script: PathBuf) -> Result<Self> {
+    pub fn new(
+        client: ClientHandle,
+        event_sender: EventSender,
+        settings: settings::Shared,
+        log_file: Option<PathBuf>,
+    ) -> Result<Self> {
         Ok(Self {
+            client,
             event_sender,
-            rustc,
-            rust_script: rust_script.into(),
+            settings,
+            log_file: log_file.map(Arc::new),
             scripts: BTreeMap::new(),
+            ejected: BTreeMap::new(),
         })
     }
 
     pub async fn register(&mut self, uri: lsp_types::Url) {
         if let Ok(file) = uri.to_file_path() {
-            if let std::collections::btree_map::Entry::Vacant(entry) = self.scripts.entry(uri) {
-                let script = entry.insert(Arc::new(
-                    Script::new(file, &self.rustc, self.rust_script.clone()).await,
-                ));
-                let sender = self.event_sender.clone();
-                sender.mark_need_reload();
-                script
-                    .queue_refresh(move || sender.mark_need_reload())
-                    .await
+            if !self.scripts.contains_key(&uri) {
+                let script = Arc::new(Script::new(self, uri.clone(), file));
+                script.load_directives().await;
+                self.scripts.insert(uri, script.clone());
+                self.event_sender.mark_need_reload();
+                if self.settings.for_path(&script.source).refresh != Refresh::Manual {
+                    Self::spawn_refresh(script.clone(), self.event_sender.clone());
+                }
             }
         }
     }
 
     pub fn deregister_if_registered(&mut self, uri: &lsp_types::Url) {
-        if self.scripts.remove(uri).is_some() {
+        let removed = self.scripts.remove(uri).is_some();
+        if self.ejected.remove(uri).is_some() || removed {
             self.event_sender.mark_need_reload();
         }
     }
 
+    /// Replaces the script with the Cargo project it was ejected into.
+    pub fn eject(&mut self, uri: &lsp_types::Url, manifest_path: PathBuf) {
+        self.scripts.remove(uri);
+        self.ejected.insert(uri.clone(), manifest_path);
+        self.event_sender.mark_need_reload();
+    }
+
     pub async fn queue_refresh(&self, uri: &lsp_types::Url) {
         if let Some(script) = self.scripts.get(uri) {
-            let sender = self.event_sender.clone();
+            Self::spawn_refresh(script.clone(), self.event_sender.clone());
+        }
+    }
+
+    pub async fn queue_refresh_all(&self) {
+        for script in self.scripts.values() {
+            Self::spawn_refresh(script.clone(), self.event_sender.clone());
+        }
+    }
+
+    /// Reloads the scripts as needed for the change of the settings.
+    pub fn reconfigure(&self, old: &settings::Layers) {
+        for script in self.scripts.values() {
+            let old = old.resolve(Some(&script.source));
+            let new = self.settings.for_path(&script.source);
+            if old.rust_script != new.rust_script
+                || old.profiles.rust_script != new.profiles.rust_script
+                || old.backend != new.backend
+                || old.target_dir != new.target_dir
+            {
+                Self::spawn_refresh(script.clone(), self.event_sender.clone());
+                self.event_sender.mark_need_reload();
+            } else if old.script_rust_analyzer_settings != new.script_rust_analyzer_settings {
+                // Only to rewrite `rust-analyzer.toml`, which rust-analyzer watches.
+                Self::spawn_refresh(script.clone(), self.event_sender.clone());
+            } else if old.rustc != new.rustc || old.profiles.rustc != new.profiles.rustc {
+                self.event_sender.mark_need_reload();
+            }
+        }
+    }
+
+    /// Returns the scripts, to ask the client for settings of each.
+    pub fn uris(&self) -> Vec<lsp_types::Url> {
+        self.scripts.keys().cloned().collect()
+    }
+
+    /// Replaces the settings of rust-analyzer the client gives for the script, which are given as
+    /// ones different from the settings for the workspace.
+    pub fn set_scoped_settings(&self, uri: &lsp_types::Url, settings: Value) {
+        let Some(script) = self.scripts.get(uri) else {
+            return;
+        };
+        {
+            let mut scoped = script.scoped_settings.lock().unwrap();
+            if *scoped == settings {
+                return;
+            }
+            *scoped = settings;
+        }
+        // Only to rewrite `rust-analyzer.toml`, which rust-analyzer watches.
+        Self::spawn_refresh(script.clone(), self.event_sender.clone());
+    }
+
+    /// Returns the settings of rust-analyzer only for the script, if it's one.
+    pub fn rust_analyzer_settings(&self, uri: &lsp_types::Url) -> Option<Value> {
+        let script = self.scripts.get(uri)?;
+        Some(script.rust_analyzer_settings(&self.settings.for_path(&script.source)))
+    }
+
+    /// Refreshes the script in background, as it may take long and the user may cancel it through
+    /// the main loop.
+    fn spawn_refresh(script: Arc<Script>, sender: EventSender) {
+        spawn(async move {
             script
                 .queue_refresh(move || sender.mark_need_reload())
                 .await
-        }
+        });
     }
 
-    pub async fn queue_refresh_all(&self) {
-        // https://github.com/rust-lang/rust-clippy/issues/16012
-        #[allow(clippy::redundant_iter_cloned)]
+    /// Returns the path to `Cargo.toml` of the package generated for the script.
+    pub fn manifest_path(&self, uri: &lsp_types::Url) -> Option<PathBuf> {
+        self.scripts.get(uri)?.manifest_path()
+    }
+
+    /// Returns the directories of the packages generated for the scripts.
+    pub fn package_dirs(&self) -> Vec<PathBuf> {
         self.scripts
             .values()
-            .cloned()
-            .map(|script| {
-                let sender = self.event_sender.clone();
-                spawn(async move {
-                    script
-                        .queue_refresh(move || sender.mark_need_reload())
-                        .await
-                })
-            })
-            .collect::<JoinAll<_>>()
-            .await;
+            .filter_map(|script| script.manifest_path()?.parent().map(Path::to_path_buf))
+            .collect()
     }
 
     pub async fn projects(&self) -> Vec<Value> {
-        self.scripts
+        let mut projects = self
+            .scripts
             .values()
             .map(|script| script.project())
             .collect::<JoinAll<_>>()
-            .await
+            .await;
+        projects.extend(self.ejected.values().map(|manifest| json!(manifest)));
+        projects
+    }
+}
+
+/// Finds scripts under the directories, i.e. files matching the patterns and files with a
+/// rust-script or cargo shebang.
+pub async fn discover(roots: &[PathBuf], patterns: &[String]) -> Vec<PathBuf> {
+    let roots = roots.to_vec();
+    let patterns = patterns.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let mut scripts = vec![];
+        let mut dirs = roots.clone();
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                if file_type.is_dir() {
+                    if !name.starts_with('.') && name != "target" {
+                        dirs.push(path);
+                    }
+                } else if file_type.is_file() && is_script_file(&path, &roots, &patterns) {
+                    scripts.push(path);
+                }
+            }
+        }
+        scripts
+    })
+    .await
+    .unwrap_or_default()
+}
+
+fn is_script_file(path: &Path, roots: &[PathBuf], patterns: &[String]) -> bool {
+    if detection::matches_patterns(patterns, roots, path) {
+        return true;
     }
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+    let mut first_line = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(file), &mut first_line).is_ok()
+        && detection::has_shebang(&first_line)
 }
 
-async fn create_default_project(source: &PathBuf, rustc: &PathBuf) -> Value {
-    static SYSROOT: OnceCell<Result<PathBuf>> = OnceCell::const_new();
-    let sysroot = SYSROOT.get_or_init(|| default_sysroot(rustc)).await;
+async fn create_default_project(
+    source: &PathBuf,
+    settings: &Settings,
+    directives: &Directives,
+) -> Value {
+    let sysroot = sysroot(settings).await;
+    let mut krate = json!({
+        "root_module": source,
+        "edition": "2021",
+        "deps": [],
+        "is_proc_macro": false,
+    });
+    directives.apply_to_crate(krate.as_object_mut().unwrap());
     let mut value = json!({
-        "crates": [{
-            "root_module": source,
-            "edition": "2021",
-            "deps": [],
-            "is_proc_macro": false,
-        }]
+        "crates": [krate]
     });
-    if let Ok(Ok(sysroot)) = sysroot.as_ref().map(serde_json::to_value) {
+    if let Some(Ok(sysroot)) = sysroot.as_ref().map(serde_json::to_value) {
         value
             .as_object_mut()
             .unwrap()
@@ -174,15 +494,118 @@ async fn create_default_project(source: &PathBuf, rustc: &PathBuf) -> Value {
     value
 }
 
-async fn package_dir(rust_script: impl AsRef<Path>, script: impl AsRef<Path>) -> Result<PathBuf> {
-    let mut cmd = Command::new(rust_script.as_ref());
+/// Generates the package for the script and returns the directory of it.
+pub async fn package_dir(settings: &Settings, script: impl AsRef<Path>) -> Result<PathBuf> {
+    let mut cmd = settings.rust_script_command();
     cmd.arg("--package").arg(script.as_ref());
     run_and_parse_output_as_path(cmd).await
 }
 
-async fn default_sysroot(rustc: &PathBuf) -> Result<PathBuf> {
-    let mut cmd = Command::new(rustc);
-    cmd.args(["--print", "sysroot"]).current_dir("/");
+/// Same as [package_dir], but reports the stages cargo prints to the progress, through which the
+/// user can cancel it.
+async fn package_dir_with_progress(
+    settings: &Settings,
+    script: &Path,
+    progress: &Progress,
+) -> Result<PathBuf> {
+    let mut command = settings.rust_script_command();
+    command
+        .arg("--package")
+        .arg(script)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command
+        .spawn()
+        .wrap_err_with(|| eyre!("failed to run `{command:?}`"))?;
+    let mut stdout = child.stdout.take().map(BufReader::new);
+    let stderr = child.stderr.take();
+    let read_stdout = async {
+        let mut output = String::new();
+        if let Some(stdout) = &mut stdout {
+            stdout.read_to_string(&mut output).await.ok();
+        }
+        output
+    };
+    let read_stderr = async {
+        let mut output = String::new();
+        let Some(stderr) = stderr else {
+            return output;
+        };
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some(stage) = stage(&line) {
+                progress.report(stage);
+            }
+            output.push_str(&line);
+            output.push('\n');
+        }
+        output
+    };
+    let (status, stdout, stderr) = tokio::select! {
+        (status, stdout, stderr) = futures::future::join3(child.wait(), read_stdout, read_stderr) => {
+            (status.wrap_err("failed to wait for rust-script")?, stdout, stderr)
+        }
+        _ = progress.cancelled() => bail!("cancelled"),
+    };
+    ensure!(
+        status.success(),
+        "`{} --package {}` failed with {status}:\n{}",
+        settings.rust_script.display(),
+        script.display(),
+        trim_stderr(&stderr),
+    );
+    parse_output_as_path(stdout)
+}
+
+/// Keeps the last lines of the stderr, where cargo tells what went wrong.
+fn trim_stderr(stderr: &str) -> String {
+    const MAX_LINES: usize = 20;
+    let lines = stderr.trim().lines().collect::<Vec<_>>();
+    let skipped = lines.len().saturating_sub(MAX_LINES);
+    let mut trimmed = lines[skipped..].join("\n");
+    if skipped > 0 {
+        trimmed.insert_str(0, "...\n");
+    }
+    trimmed
+}
+
+/// Extracts a stage from a status line of cargo, e.g. `Downloading 3 crates` from
+/// `  Downloading 3 crates ...`.
+fn stage(line: &str) -> Option<String> {
+    let line = line.trim();
+    let (verb, rest) = line.split_once(' ')?;
+    let is_status = verb.len() > 1
+        && verb.starts_with(|c: char| c.is_ascii_uppercase())
+        && verb[1..].chars().all(|c| c.is_ascii_lowercase());
+    is_status.then(|| format!("{verb} {}", rest.trim().trim_end_matches(" ...")))
+}
+
+/// Returns the sysroot of the rustc, remembering it for each rustc as it's asked for each script.
+async fn sysroot(settings: &Settings) -> Option<PathBuf> {
+    type Key = (PathBuf, settings::Profile);
+    static SYSROOTS: Mutex<BTreeMap<Key, Option<PathBuf>>> = Mutex::const_new(BTreeMap::new());
+    let key = (settings.rustc.clone(), settings.profiles.rustc.clone());
+    let mut sysroots = SYSROOTS.lock().await;
+    if let Some(sysroot) = sysroots.get(&key) {
+        return sysroot.clone();
+    }
+    let sysroot = default_sysroot(settings)
+        .await
+        .inspect_err(|e| tracing::warn!(rustc = ?settings.rustc, ?e, "failed to identify sysroot"))
+        .ok();
+    sysroots.insert(key, sysroot.clone());
+    sysroot
+}
+
+async fn default_sysroot(settings: &Settings) -> Result<PathBuf> {
+    let mut cmd = settings.rustc_command();
+    cmd.args(["--print", "sysroot"]);
+    // Not to be affected by `rust-toolchain.toml` wherever rscls runs, unless the profile says.
+    if settings.profiles.rustc.cwd.is_none() {
+        cmd.current_dir("/");
+    }
     run_and_parse_output_as_path(cmd).await
 }
 
@@ -198,6 +621,10 @@ async fn run_and_parse_output_as_path(mut command: Command) -> Result<PathBuf> {
         String::from_utf8_lossy(&output.stderr)
     );
     let output = String::from_utf8(output.stdout).wrap_err("got an invalid path")?;
+    parse_output_as_path(output)
+}
+
+fn parse_output_as_path(output: String) -> Result<PathBuf> {
     let path = PathBuf::from(output.trim_end());
     let path = path.absolutize().wrap_err("got an invalid abs path")?;
     Ok(path.to_path_buf())
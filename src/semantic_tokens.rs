@@ -0,0 +1,518 @@
+use std::{collections::HashMap, ops::Range};
+
+use lsp_types::{
+    request::{Request as _, SemanticTokensFullRequest},
+    SemanticToken, SemanticTokenModifier, SemanticTokenType, SemanticTokens, SemanticTokensDelta,
+    SemanticTokensDeltaParams, SemanticTokensEdit, SemanticTokensFullDeltaResult,
+    SemanticTokensLegend, SemanticTokensParams, Url,
+};
+use serde_json::Value;
+
+use crate::{
+    document::{Document, Documents, Encoding},
+    manifest::{Manifest, DEPENDENCY_TABLES},
+};
+
+/// Overlays TOML highlighting of the embedded manifest onto the semantic tokens of rust-analyzer.
+#[derive(Default)]
+pub struct Overlay {
+    legend: Option<SemanticTokensLegend>,
+    /// The last tokens sent to the client for each script, to compute deltas against.
+    sent: HashMap<Url, (String, Vec<SemanticToken>)>,
+}
+
+impl Overlay {
+    /// Picks up the legend rust-analyzer uses from its server capabilities.
+    pub fn set_legend(&mut self, capabilities: &Value) {
+        self.legend = capabilities
+            .get("semanticTokensProvider")
+            .and_then(|provider| provider.get("legend"))
+            .and_then(|legend| serde_json::from_value(legend.clone()).ok());
+    }
+
+    pub fn forget(&mut self, uri: &Url) {
+        self.sent.remove(uri);
+    }
+
+    /// Overlays tokens of the manifest onto tokens of the whole document.
+    pub fn full(
+        &mut self,
+        uri: &Url,
+        document: &Document,
+        encoding: Encoding,
+        mut tokens: SemanticTokens,
+    ) -> SemanticTokens {
+        tokens.data = self.overlay(document, encoding, &tokens.data, None);
+        match &tokens.result_id {
+            Some(result_id) => {
+                self.sent
+                    .insert(uri.clone(), (result_id.clone(), tokens.data.clone()));
+            }
+            None => self.forget(uri),
+        }
+        tokens
+    }
+
+    /// Same as [Self::full], but returns the difference from the previous result if possible.
+    pub fn delta(
+        &mut self,
+        uri: &Url,
+        previous_result_id: &str,
+        document: &Document,
+        encoding: Encoding,
+        tokens: SemanticTokens,
+    ) -> SemanticTokensFullDeltaResult {
+        let previous = self.sent.get(uri).cloned();
+        let tokens = self.full(uri, document, encoding, tokens);
+        match (previous, &tokens.result_id) {
+            (Some((previous_id, previous)), Some(result_id))
+                if previous_id == previous_result_id =>
+            {
+                SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                    result_id: Some(result_id.clone()),
+                    edits: diff(&previous, &tokens.data).into_iter().collect(),
+                })
+            }
+            _ => SemanticTokensFullDeltaResult::Tokens(tokens),
+        }
+    }
+
+    /// Overlays tokens of the manifest onto tokens of the `lines`.
+    pub fn range(
+        &self,
+        document: &Document,
+        encoding: Encoding,
+        mut tokens: SemanticTokens,
+        lines: Range<u32>,
+    ) -> SemanticTokens {
+        tokens.data = self.overlay(document, encoding, &tokens.data, Some(lines));
+        tokens
+    }
+
+    fn overlay(
+        &self,
+        document: &Document,
+        encoding: Encoding,
+        tokens: &[SemanticToken],
+        lines: Option<Range<u32>>,
+    ) -> Vec<SemanticToken> {
+        let (Some(legend), Some(manifest)) = (&self.legend, Manifest::find(document)) else {
+            return tokens.to_vec();
+        };
+        let manifest_lines = manifest.start_line + 1..manifest.end_line;
+        let mut absolute = decode(tokens);
+        absolute.retain(|token| !manifest_lines.contains(&token.line));
+        let legend = Legend(legend);
+        let mut lexer = Lexer::default();
+        for line in &manifest.lines {
+            let toml_tokens = lexer.lex_line(&line.text);
+            if lines
+                .as_ref()
+                .is_some_and(|lines| !lines.contains(&line.line))
+            {
+                continue;
+            }
+            let Some(text) = document.line(line.line) else {
+                continue;
+            };
+            let mut push = |range: Range<usize>, kind: Kind| {
+                if let Some((token_type, modifiers)) = legend.get(kind) {
+                    absolute.push(AbsoluteToken {
+                        line: line.line,
+                        start: encoding.str_len(&text[..range.start]),
+                        length: encoding.str_len(&text[range]),
+                        token_type,
+                        modifiers,
+                    });
+                }
+            };
+            if let Some(prefix) = text.find("//!") {
+                push(prefix..prefix + 3, Kind::DocComment);
+            }
+            for (range, kind) in toml_tokens {
+                push(line.column + range.start..line.column + range.end, kind);
+            }
+        }
+        absolute.sort_by_key(|token| (token.line, token.start));
+        encode(&absolute)
+    }
+}
+
+/// Makes the client ask rust-analyzer for the full tokens of a script, so that we can overlay
+/// manifest tokens on them and compute the delta by ourselves.
+pub fn request_full_instead_of_delta(request: &mut lsp_server::Request, documents: &Documents) {
+    let Ok(params) = serde_json::from_value::<SemanticTokensDeltaParams>(request.params.clone())
+    else {
+        return;
+    };
+    if documents.get(&params.text_document.uri).is_none() {
+        return;
+    }
+    *request = lsp_server::Request::new(
+        request.id.clone(),
+        SemanticTokensFullRequest::METHOD.to_owned(),
+        SemanticTokensParams {
+            work_done_progress_params: params.work_done_progress_params,
+            partial_result_params: params.partial_result_params,
+            text_document: params.text_document,
+        },
+    );
+}
+
+fn diff(old: &[SemanticToken], new: &[SemanticToken]) -> Option<SemanticTokensEdit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let deleted = old.len() - prefix - suffix;
+    let inserted = &new[prefix..new.len() - suffix];
+    if deleted == 0 && inserted.is_empty() {
+        return None;
+    }
+    // Each token consists of 5 integers.
+    Some(SemanticTokensEdit {
+        start: 5 * prefix as u32,
+        delete_count: 5 * deleted as u32,
+        data: Some(inserted.to_vec()),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AbsoluteToken {
+    line: u32,
+    start: u32,
+    length: u32,
+    token_type: u32,
+    modifiers: u32,
+}
+
+fn decode(tokens: &[SemanticToken]) -> Vec<AbsoluteToken> {
+    let (mut line, mut start) = (0, 0);
+    tokens
+        .iter()
+        .map(|token| {
+            if token.delta_line > 0 {
+                start = 0;
+            }
+            line += token.delta_line;
+            start += token.delta_start;
+            AbsoluteToken {
+                line,
+                start,
+                length: token.length,
+                token_type: token.token_type,
+                modifiers: token.token_modifiers_bitset,
+            }
+        })
+        .collect()
+}
+
+fn encode(tokens: &[AbsoluteToken]) -> Vec<SemanticToken> {
+    let (mut line, mut start) = (0, 0);
+    tokens
+        .iter()
+        .map(|token| {
+            let delta_line = token.line - line;
+            let delta_start = if delta_line > 0 {
+                token.start
+            } else {
+                token.start - start
+            };
+            (line, start) = (token.line, token.start);
+            SemanticToken {
+                delta_line,
+                delta_start,
+                length: token.length,
+                token_type: token.token_type,
+                token_modifiers_bitset: token.modifiers,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    DocComment,
+    Comment,
+    Table,
+    Key,
+    String,
+    Version,
+    Number,
+    Boolean,
+}
+
+struct Legend<'a>(&'a SemanticTokensLegend);
+
+impl Legend<'_> {
+    fn token_type(&self, candidates: &[SemanticTokenType]) -> Option<u32> {
+        candidates.iter().find_map(|candidate| {
+            let index = self.0.token_types.iter().position(|t| t == candidate)?;
+            Some(index as u32)
+        })
+    }
+
+    fn modifier(&self, modifier: &SemanticTokenModifier) -> u32 {
+        match self.0.token_modifiers.iter().position(|m| m == modifier) {
+            Some(index) => 1 << index,
+            None => 0,
+        }
+    }
+
+    fn get(&self, kind: Kind) -> Option<(u32, u32)> {
+        let token_type = match kind {
+            Kind::DocComment | Kind::Comment => self.token_type(&[SemanticTokenType::COMMENT]),
+            Kind::Table => self.token_type(&[SemanticTokenType::NAMESPACE]),
+            Kind::Key => self.token_type(&[SemanticTokenType::PROPERTY]),
+            Kind::String => self.token_type(&[SemanticTokenType::STRING]),
+            Kind::Version | Kind::Number => self.token_type(&[SemanticTokenType::NUMBER]),
+            Kind::Boolean => self.token_type(&[
+                SemanticTokenType::new("boolean"),
+                SemanticTokenType::KEYWORD,
+            ]),
+        }?;
+        let modifiers = match kind {
+            Kind::DocComment => self.modifier(&SemanticTokenModifier::DOCUMENTATION),
+            _ => 0,
+        };
+        Some((token_type, modifiers))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Nesting {
+    Array,
+    InlineTable,
+}
+
+/// A line-based TOML lexer, which is good enough for highlighting.
+#[derive(Default)]
+struct Lexer {
+    nesting: Vec<Nesting>,
+    expect_key: bool,
+    key: String,
+    in_dependency_table: bool,
+    multiline_string: Option<&'static str>,
+}
+
+impl Lexer {
+    fn lex_line(&mut self, text: &str) -> Vec<(Range<usize>, Kind)> {
+        let mut tokens = vec![];
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        if let Some(delimiter) = self.multiline_string {
+            match text.find(delimiter) {
+                Some(end) => {
+                    tokens.push((0..end + 3, Kind::String));
+                    self.multiline_string = None;
+                    i = end + 3;
+                }
+                None => {
+                    tokens.push((0..text.len(), Kind::String));
+                    return tokens;
+                }
+            }
+        }
+        if self.nesting.is_empty() {
+            self.expect_key = true;
+        }
+        while i < bytes.len() {
+            match bytes[i] {
+                b'#' => {
+                    tokens.push((i..text.len(), Kind::Comment));
+                    break;
+                }
+                b'[' if self.nesting.is_empty() && self.expect_key => {
+                    let start = i + text[i..].bytes().take_while(|b| *b == b'[').count();
+                    let end = text[start..]
+                        .find(']')
+                        .map_or(text.len(), |end| start + end);
+                    let name = text[start..end].trim();
+                    let name_start = start + text[start..].find(name).unwrap_or(0);
+                    tokens.push((name_start..name_start + name.len(), Kind::Table));
+                    self.in_dependency_table = name
+                        .rsplit('.')
+                        .next()
+                        .is_some_and(|last| DEPENDENCY_TABLES.contains(&last.trim()));
+                    i = end + text[end..].bytes().take_while(|b| *b == b']').count();
+                }
+                b'[' => {
+                    self.nesting.push(Nesting::Array);
+                    self.expect_key = false;
+                    i += 1;
+                }
+                b'{' => {
+                    self.nesting.push(Nesting::InlineTable);
+                    self.expect_key = true;
+                    i += 1;
+                }
+                b']' | b'}' => {
+                    self.nesting.pop();
+                    i += 1;
+                }
+                b'=' => {
+                    self.expect_key = false;
+                    i += 1;
+                }
+                b',' => {
+                    self.expect_key = self.nesting.last() == Some(&Nesting::InlineTable);
+                    i += 1;
+                }
+                quote @ (b'"' | b'\'') => {
+                    let triple = if quote == b'"' { "\"\"\"" } else { "'''" };
+                    let end = if text[i..].starts_with(triple) {
+                        match text[i + 3..].find(triple) {
+                            Some(end) => i + 3 + end + 3,
+                            None => {
+                                self.multiline_string = Some(triple);
+                                text.len()
+                            }
+                        }
+                    } else {
+                        string_end(text, i, quote)
+                    };
+                    let kind = self.word_kind(&text[i..end], true);
+                    tokens.push((i..end, kind));
+                    i = end;
+                }
+                b if b.is_ascii_whitespace() || b == b'.' => i += 1,
+                _ => {
+                    let is_word = |b: &u8| {
+                        b.is_ascii_alphanumeric()
+                            || b"_-+:".contains(b)
+                            || (*b == b'.' && !self.expect_key)
+                    };
+                    let len = match bytes[i..].iter().take_while(|b| is_word(b)).count() {
+                        // Step over a whole character, which may not be ASCII.
+                        0 => text[i..].chars().next().map_or(1, char::len_utf8),
+                        len => len,
+                    };
+                    let kind = self.word_kind(&text[i..i + len], false);
+                    tokens.push((i..i + len, kind));
+                    i += len;
+                }
+            }
+        }
+        tokens
+    }
+
+    fn word_kind(&mut self, word: &str, quoted: bool) -> Kind {
+        if self.expect_key {
+            self.key = word.trim_matches(['"', '\'']).to_owned();
+            Kind::Key
+        } else if quoted {
+            let is_version =
+                self.key == "version" || (self.in_dependency_table && self.nesting.is_empty());
+            if is_version {
+                Kind::Version
+            } else {
+                Kind::String
+            }
+        } else if word == "true" || word == "false" {
+            Kind::Boolean
+        } else {
+            Kind::Number
+        }
+    }
+}
+
+/// Returns the end of the single-line string starting at `start`.
+fn string_end(text: &str, start: usize, quote: u8) -> usize {
+    let mut escaped = false;
+    for (i, b) in text.bytes().enumerate().skip(start + 1) {
+        match b {
+            b'\\' if quote == b'"' && !escaped => escaped = true,
+            b if b == quote && !escaped => return i + 1,
+            _ => escaped = false,
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(lines: &[&str]) -> Vec<Vec<(Range<usize>, Kind)>> {
+        let mut lexer = Lexer::default();
+        lines.iter().map(|line| lexer.lex_line(line)).collect()
+    }
+
+    #[test]
+    fn lex_non_ascii() {
+        assert_eq!(
+            lex(&["foo = é", "bar = \"é\""]),
+            [
+                vec![(0..3, Kind::Key), (6..8, Kind::Number)],
+                vec![(0..3, Kind::Key), (6..10, Kind::String)],
+            ],
+        );
+    }
+
+    #[test]
+    fn lex_multiline_string() {
+        assert_eq!(
+            lex(&[
+                "description = \"\"\"foo",
+                "bar",
+                "baz\"\"\" # end",
+                "edition = 2021"
+            ]),
+            [
+                vec![(0..11, Kind::Key), (14..20, Kind::String)],
+                vec![(0..3, Kind::String)],
+                vec![(0..6, Kind::String), (7..12, Kind::Comment)],
+                vec![(0..7, Kind::Key), (10..14, Kind::Number)],
+            ],
+        );
+    }
+
+    #[test]
+    fn lex_table_headers() {
+        assert_eq!(
+            lex(&[
+                "[package]",
+                "name = \"foo\"",
+                "[ target.'cfg(unix)'.dependencies ]",
+                "libc = \"0.2\"",
+                "serde = { version = \"1\", features = [\"derive\"] }",
+            ]),
+            [
+                vec![(1..8, Kind::Table)],
+                vec![(0..4, Kind::Key), (7..12, Kind::String)],
+                vec![(2..33, Kind::Table)],
+                vec![(0..4, Kind::Key), (7..12, Kind::Version)],
+                vec![
+                    (0..5, Kind::Key),
+                    (10..17, Kind::Key),
+                    (20..23, Kind::Version),
+                    (25..33, Kind::Key),
+                    (37..45, Kind::String),
+                ],
+            ],
+        );
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let tokens = [(0, 0, 3), (0, 4, 2), (2, 1, 5), (2, 10, 1), (5, 0, 4)].map(
+            |(line, start, length)| AbsoluteToken {
+                line,
+                start,
+                length,
+                token_type: line % 3,
+                modifiers: start % 2,
+            },
+        );
+        let encoded = encode(&tokens);
+        assert_eq!(encoded[1].delta_line, 0);
+        assert_eq!(encoded[1].delta_start, 4);
+        assert_eq!(encoded[2].delta_line, 2);
+        assert_eq!(encoded[2].delta_start, 1);
+        assert_eq!(encoded[3].delta_start, 9);
+        assert_eq!(decode(&encoded), tokens);
+    }
+}
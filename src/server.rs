@@ -1,11 +1,9 @@
-use std::ffi::OsStr;
-
 use eyre::{Context as _, Result};
 use futures::{sink::SinkExt as _, stream::TryStreamExt as _};
 use lsp_server::Message;
 use tokio::{
     io::{AsyncBufReadExt as _, BufReader},
-    process::{Child, ChildStderr, ChildStdin, ChildStdout},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
     spawn,
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     task::JoinHandle,
@@ -27,8 +25,8 @@ pub struct Server {
 }
 
 impl Server {
-    pub fn spawn(event_sender: EventSender, path: impl AsRef<OsStr>) -> Result<Self> {
-        let mut process = tokio::process::Command::new(path)
+    pub fn spawn(event_sender: EventSender, mut command: Command) -> Result<Self> {
+        let mut process = command
             .kill_on_drop(true)
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
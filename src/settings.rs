@@ -0,0 +1,509 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use eyre::{bail, eyre, Result, WrapErr as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::process::Command;
+
+/// The section of settings for rscls, in `initializationOptions` and `workspace/configuration`.
+pub const SECTION: &str = "rscls";
+
+/// Settings which make rscls run programs, and thus are ignored in project files out of
+/// [Settings::trusted_dirs].
+pub const NEED_TRUST: [&str; 7] = [
+    "rustScript",
+    "rustAnalyzer",
+    "rustc",
+    "rustAnalyzerSettings",
+    "scriptRustAnalyzerSettings",
+    "profiles",
+    "trustedDirs",
+];
+
+/// Settings of rscls itself, as opposed to the ones of rust-analyzer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    pub rust_script: PathBuf,
+    /// Only takes effect on startup, as rust-analyzer is not restarted.
+    pub rust_analyzer: PathBuf,
+    pub rustc: PathBuf,
+    pub template_dir: Option<PathBuf>,
+    /// Language ids of documents to handle as scripts.
+    pub language_ids: Vec<String>,
+    /// Glob patterns of paths of documents to handle as scripts, see
+    /// [crate::detection::matches_patterns].
+    pub patterns: Vec<String>,
+    pub refresh: Refresh,
+    pub backend: Backend,
+    pub features: Features,
+    /// The target directory shared by all the scripts, instead of one for each.
+    pub target_dir: Option<PathBuf>,
+    /// Settings of rust-analyzer to add for scripts, unless the client gives them.
+    pub rust_analyzer_settings: Value,
+    /// Settings of rust-analyzer only for the packages generated for scripts, which are written
+    /// into their `rust-analyzer.toml`.
+    pub script_rust_analyzer_settings: Value,
+    /// Directories whose project files are trusted to run programs, see [NEED_TRUST].
+    pub trusted_dirs: Vec<PathBuf>,
+    /// Whether to link Cargo workspaces in the workspace folders along with scripts, as
+    /// rust-analyzer doesn't discover them once `linkedProjects` is given.
+    pub discover_projects: bool,
+    /// How to run each of the programs.
+    pub profiles: Profiles,
+}
+
+/// When to let rust-script regenerate the package of a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Refresh {
+    /// When the script is opened or saved.
+    OnSave,
+    /// Only when the script is opened.
+    OnOpen,
+    /// Only when the client asks to reload the workspace.
+    Manual,
+}
+
+/// How to turn scripts into projects rust-analyzer understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Backend {
+    /// Packages generated by rust-script, with dependencies.
+    RustScript,
+    /// A crate of the script alone, without running rust-script at all.
+    Standalone,
+}
+
+/// Switches of what rscls adds on top of rust-analyzer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Features {
+    pub semantic_tokens: bool,
+    pub inlay_hints: bool,
+    pub document_links: bool,
+    pub code_actions: bool,
+    pub code_lenses: bool,
+    pub runnables: bool,
+    pub formatting: bool,
+    pub diagnostics: bool,
+}
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            semantic_tokens: true,
+            inlay_hints: true,
+            document_links: true,
+            code_actions: true,
+            code_lenses: true,
+            runnables: true,
+            formatting: true,
+            diagnostics: true,
+        }
+    }
+}
+
+/// Invocation profiles of the programs rscls runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Profiles {
+    /// Only takes effect on startup, as rust-analyzer is not restarted.
+    pub rust_analyzer: Profile,
+    pub rust_script: Profile,
+    pub rustc: Profile,
+    pub cargo: Profile,
+}
+
+impl Profiles {
+    /// Applies `<TOOL>.<FIELD>=<VALUE>` given by `--profile`, where `TOOL` can be `all`.
+    pub fn apply_arg(&mut self, arg: &str) -> Result<()> {
+        let (target, value) = arg
+            .split_once('=')
+            .ok_or_else(|| eyre!("expected `<TOOL>.<FIELD>=<VALUE>`"))?;
+        let (tool, field) = target
+            .split_once('.')
+            .ok_or_else(|| eyre!("expected `<TOOL>.<FIELD>` before `=`"))?;
+        let profiles = match tool {
+            "rust-analyzer" => vec![&mut self.rust_analyzer],
+            "rust-script" => vec![&mut self.rust_script],
+            "rustc" => vec![&mut self.rustc],
+            "cargo" => vec![&mut self.cargo],
+            "all" => vec![
+                &mut self.rust_analyzer,
+                &mut self.rust_script,
+                &mut self.rustc,
+                &mut self.cargo,
+            ],
+            _ => bail!("unknown tool `{tool}`"),
+        };
+        for profile in profiles {
+            profile.apply_arg(field, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// How to run a program, on top of what rscls passes to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Profile {
+    /// Arguments passed before the ones from rscls, e.g. `["--log-file", "ra.log"]`.
+    pub args: Vec<String>,
+    /// Environment variables to set, e.g. `{ "RUSTUP_TOOLCHAIN": "nightly" }`.
+    pub env: BTreeMap<String, String>,
+    /// The working directory, unless rscls runs it in a particular one, e.g. the directory of a
+    /// script.
+    pub cwd: Option<PathBuf>,
+    /// A command to run the program through, e.g. `["nix", "develop", "-c"]`.
+    pub wrapper: Vec<String>,
+}
+impl Profile {
+    fn apply_arg(&mut self, field: &str, value: &str) -> Result<()> {
+        match field {
+            "arg" => self.args.push(value.to_owned()),
+            "env" => {
+                let (key, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| eyre!("expected `env=<KEY>=<VALUE>`"))?;
+                self.env.insert(key.to_owned(), value.to_owned());
+            }
+            "cwd" => self.cwd = Some(value.into()),
+            "wrapper" => self.wrapper = value.split_whitespace().map(str::to_owned).collect(),
+            _ => bail!("unknown field `{field}`, expected `arg`, `env`, `cwd` or `wrapper`"),
+        }
+        Ok(())
+    }
+
+    /// Creates a command to run the program with the profile.
+    pub fn command(&self, program: impl AsRef<std::ffi::OsStr>) -> Command {
+        let mut command = match self.wrapper.split_first() {
+            Some((wrapper, wrapper_args)) => {
+                let mut command = Command::new(wrapper);
+                command.args(wrapper_args).arg(program);
+                command
+            }
+            None => Command::new(program),
+        };
+        command.args(&self.args).envs(&self.env);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command
+    }
+}
+
+impl Settings {
+    pub fn new(rust_script: PathBuf, rust_analyzer: PathBuf, rustc: PathBuf) -> Self {
+        Self {
+            rust_script,
+            rust_analyzer,
+            rustc,
+            template_dir: None,
+            language_ids: ["rustscript", "rust-script", "rust_script"]
+                .map(str::to_owned)
+                .into(),
+            patterns: vec!["*.ers".to_owned()],
+            refresh: Refresh::OnSave,
+            backend: Backend::RustScript,
+            features: Features::default(),
+            target_dir: None,
+            rust_analyzer_settings: Value::Null,
+            script_rust_analyzer_settings: Value::Null,
+            trusted_dirs: vec![],
+            discover_projects: true,
+            profiles: Profiles::default(),
+        }
+    }
+
+    /// Returns the settings overridden by the given ones, e.g. `{ "features": { "inlayHints":
+    /// false } }`, where missing or `null` fields are kept as is.
+    pub fn merge(&self, overrides: &Value) -> Result<Self> {
+        let mut value = serde_json::to_value(self)?;
+        merge(&mut value, overrides);
+        serde_json::from_value(value).wrap_err("invalid settings")
+    }
+
+    pub fn is_trusted(&self, dir: &Path) -> bool {
+        self.trusted_dirs
+            .iter()
+            .any(|trusted| dir.starts_with(trusted))
+    }
+
+    /// Adds what we have to say to the settings of rust-analyzer, unless they're already given.
+    pub fn fill_rust_analyzer_settings(&self, opts: &mut Value) {
+        fill(opts, &self.rust_analyzer_settings);
+        if let Some(target_dir) = &self.target_dir {
+            fill(
+                opts,
+                &serde_json::json!({ "cargo": { "targetDir": target_dir } }),
+            );
+        }
+    }
+
+    /// Creates a command to run rust-analyzer as the server.
+    pub fn rust_analyzer_command(&self) -> Command {
+        self.profiles.rust_analyzer.command(&self.rust_analyzer)
+    }
+
+    /// Creates a command to run rust-script.
+    pub fn rust_script_command(&self) -> Command {
+        self.cargo_env(self.profiles.rust_script.command(&self.rust_script))
+    }
+
+    /// Creates a command to run rustc.
+    pub fn rustc_command(&self) -> Command {
+        self.profiles.rustc.command(&self.rustc)
+    }
+
+    /// Creates a command to run cargo, e.g. on packages generated by rust-script.
+    pub fn cargo_command(&self) -> Command {
+        self.cargo_env(self.profiles.cargo.command("cargo"))
+    }
+
+    fn cargo_env(&self, mut command: Command) -> Command {
+        if let Some(target_dir) = &self.target_dir {
+            command.env("CARGO_TARGET_DIR", target_dir);
+        }
+        command
+    }
+}
+
+/// Overrides `base` by `overrides` recursively, where `null` keeps what's in `base`.
+pub fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (_, Value::Null) => {}
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// Same as [merge], but keeps what's already in `base`.
+fn fill(base: &mut Value, defaults: &Value) {
+    match (base, defaults) {
+        (Value::Object(base), Value::Object(defaults)) => {
+            for (key, value) in defaults {
+                fill(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (base @ Value::Null, defaults) => *base = defaults.clone(),
+        _ => {}
+    }
+}
+
+/// Returns the part of `scoped` different from `base`, or `null` if none.
+pub fn diff(base: &Value, scoped: &Value) -> Value {
+    match (base, scoped) {
+        (Value::Object(base), Value::Object(scoped)) => {
+            let diff = scoped
+                .iter()
+                .filter_map(|(key, value)| {
+                    let diff = match base.get(key) {
+                        Some(base) => diff(base, value),
+                        None => value.clone(),
+                    };
+                    (!diff.is_null()).then(|| (key.clone(), diff))
+                })
+                .collect::<serde_json::Map<_, _>>();
+            if diff.is_empty() {
+                Value::Null
+            } else {
+                Value::Object(diff)
+            }
+        }
+        (base, scoped) if base == scoped => Value::Null,
+        (_, scoped) => scoped.clone(),
+    }
+}
+
+/// Where settings come from, in the order of precedence from the lowest.
+#[derive(Debug, Clone)]
+pub struct Layers {
+    pub command_line: Settings,
+    /// The `rscls` sections of project files by their directories.
+    pub files: BTreeMap<PathBuf, Value>,
+    pub initialization_options: Value,
+    pub configuration: Value,
+    /// Workspace folders, whose project files apply to everything.
+    pub roots: Vec<PathBuf>,
+}
+impl Layers {
+    /// Resolves the settings for the file, or for the whole workspace if `None`.
+    pub fn resolve(&self, path: Option<&Path>) -> Settings {
+        let client = [&self.initialization_options, &self.configuration];
+        let trust = self.trust();
+        let files = self
+            .files
+            .iter()
+            .filter(|(dir, _)| match path {
+                Some(path) => path.starts_with(dir),
+                None => self.roots.iter().any(|root| root.starts_with(dir)),
+            })
+            .map(|(dir, file)| {
+                let mut file = file.clone();
+                if let (false, Some(file)) = (trust.is_trusted(dir), file.as_object_mut()) {
+                    file.retain(|key, _| !NEED_TRUST.contains(&key.as_str()));
+                }
+                file
+            })
+            .collect::<Vec<_>>();
+        self.merge_all(&self.command_line, files.iter().chain(client))
+    }
+
+    /// Returns the settings to decide which project files to trust by, i.e. the ones given by the
+    /// user alone, as project files can't trust themselves.
+    pub fn trust(&self) -> Settings {
+        self.merge_all(
+            &self.command_line,
+            [&self.initialization_options, &self.configuration],
+        )
+    }
+
+    fn merge_all<'a>(
+        &self,
+        base: &Settings,
+        layers: impl IntoIterator<Item = &'a Value>,
+    ) -> Settings {
+        let mut settings = base.clone();
+        for layer in layers {
+            match settings.merge(layer) {
+                Ok(merged) => settings = merged,
+                Err(e) => tracing::warn!(?e, ?layer, "ignoring invalid settings"),
+            }
+        }
+        settings
+    }
+}
+
+/// The current settings, shared with background tasks so that they see changes made afterwards.
+#[derive(Clone)]
+pub struct Shared(Arc<RwLock<(Layers, Arc<Settings>)>>);
+impl Shared {
+    pub fn new(command_line: Settings) -> Self {
+        let settings = Arc::new(command_line.clone());
+        let layers = Layers {
+            command_line,
+            files: BTreeMap::new(),
+            initialization_options: Value::Null,
+            configuration: Value::Null,
+            roots: vec![],
+        };
+        Self(Arc::new(RwLock::new((layers, settings))))
+    }
+
+    /// Returns the settings for the whole workspace.
+    pub fn get(&self) -> Arc<Settings> {
+        self.0.read().unwrap().1.clone()
+    }
+
+    /// Returns the settings for the file, taking project files around it into account.
+    pub fn for_path(&self, path: &Path) -> Settings {
+        self.0.read().unwrap().0.resolve(Some(path))
+    }
+
+    pub fn layers(&self) -> Layers {
+        self.0.read().unwrap().0.clone()
+    }
+
+    /// Updates the layers, and returns the previous ones.
+    pub fn update(&self, update: impl FnOnce(&mut Layers)) -> Layers {
+        let mut guard = self.0.write().unwrap();
+        let old = guard.0.clone();
+        update(&mut guard.0);
+        guard.1 = Arc::new(guard.0.resolve(None));
+        old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings::new("rust-script".into(), "rust-analyzer".into(), "rustc".into())
+    }
+
+    #[test]
+    fn merge_overrides_recursively() {
+        let mut base = json!({ "a": { "b": 1, "c": [1, 2] }, "d": "x" });
+        merge(
+            &mut base,
+            &json!({ "a": { "c": [3], "e": true }, "d": null, "f": 2 }),
+        );
+        assert_eq!(
+            base,
+            json!({ "a": { "b": 1, "c": [3], "e": true }, "d": "x", "f": 2 }),
+        );
+    }
+
+    #[test]
+    fn merge_settings() {
+        let merged = settings()
+            .merge(&json!({ "features": { "inlayHints": false }, "refresh": "manual" }))
+            .unwrap();
+        assert!(!merged.features.inlay_hints);
+        assert!(merged.features.semantic_tokens);
+        assert_eq!(merged.refresh, Refresh::Manual);
+        assert_eq!(merged.rust_script, PathBuf::from("rust-script"));
+        assert!(settings().merge(&json!({ "refresh": "never" })).is_err());
+    }
+
+    #[test]
+    fn diff_from_base() {
+        let base = json!({ "check": { "command": "check", "extraArgs": [] }, "cargo": { "features": [] } });
+        assert_eq!(diff(&base, &base), Value::Null);
+        assert_eq!(
+            diff(
+                &base,
+                &json!({ "check": { "command": "clippy", "extraArgs": [] }, "cargo": { "features": [] }, "new": 1 }),
+            ),
+            json!({ "check": { "command": "clippy" }, "new": 1 }),
+        );
+        assert_eq!(diff(&json!([1]), &json!([1, 2])), json!([1, 2]));
+    }
+
+    #[test]
+    fn resolve_trusting_only_the_user() {
+        let mut layers = Layers {
+            command_line: Settings {
+                trusted_dirs: vec!["/trusted".into()],
+                ..settings()
+            },
+            files: BTreeMap::from([
+                (
+                    PathBuf::from("/trusted"),
+                    json!({ "rustc": "/a/rustc", "refresh": "manual" }),
+                ),
+                (
+                    PathBuf::from("/other"),
+                    json!({ "rustc": "/b/rustc", "refresh": "onOpen", "trustedDirs": ["/other"] }),
+                ),
+            ]),
+            initialization_options: Value::Null,
+            configuration: Value::Null,
+            roots: vec!["/trusted/workspace".into()],
+        };
+        let trusted = layers.resolve(Some(Path::new("/trusted/script.ers")));
+        assert_eq!(trusted.rustc, PathBuf::from("/a/rustc"));
+        assert_eq!(trusted.refresh, Refresh::Manual);
+        let other = layers.resolve(Some(Path::new("/other/script.ers")));
+        assert_eq!(other.rustc, PathBuf::from("rustc"));
+        assert_eq!(other.refresh, Refresh::OnOpen);
+        assert_eq!(other.trusted_dirs, [PathBuf::from("/trusted")]);
+        assert_eq!(layers.resolve(None).rustc, PathBuf::from("/a/rustc"));
+
+        layers.configuration = json!({ "trustedDirs": ["/other"] });
+        let other = layers.resolve(Some(Path::new("/other/script.ers")));
+        assert_eq!(other.rustc, PathBuf::from("/b/rustc"));
+        assert!(!layers.trust().is_trusted(Path::new("/trusted")));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use lsp_types::{
+    CodeAction, CodeActionKind, Command, Diagnostic, DiagnosticSeverity, NumberOrString, Position,
+    Range, TextEdit, Url, WorkspaceEdit,
+};
+use serde_json::json;
+
+use crate::{
+    code_action::CARGO_SHEBANG,
+    commands,
+    document::{Document, Encoding},
+};
+
+pub const RUST_SCRIPT_SHEBANG: &str = "#!/usr/bin/env rust-script";
+
+const SOURCE: &str = "rscls";
+
+/// What's wrong with the shebang of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Problem {
+    Missing,
+    /// The shebang doesn't run rust-script nor cargo.
+    Malformed,
+    /// The interpreter isn't found.
+    NotFound(String),
+    NotExecutable,
+}
+impl Problem {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Missing => "missing-shebang",
+            Self::Malformed => "malformed-shebang",
+            Self::NotFound(_) => "interpreter-not-found",
+            Self::NotExecutable => "not-executable",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::Missing => "The script has no shebang".to_owned(),
+            Self::Malformed => "The shebang runs neither rust-script nor cargo".to_owned(),
+            Self::NotFound(interpreter) => format!("`{interpreter}` is not found"),
+            Self::NotExecutable => "The script has a shebang but is not executable".to_owned(),
+        }
+    }
+
+    fn severity(&self) -> DiagnosticSeverity {
+        match self {
+            Self::Missing => DiagnosticSeverity::INFORMATION,
+            _ => DiagnosticSeverity::WARNING,
+        }
+    }
+}
+
+/// What checking a script needs to know about the filesystem, which is looked up off the main loop
+/// as it searches `PATH`. Anything not looked up is assumed to be fine.
+#[derive(Debug, Clone, Default)]
+pub struct Lookup {
+    /// Interpreters not found, among the one of the shebang and the alternatives.
+    not_found: HashSet<String>,
+    not_executable: bool,
+}
+
+/// Looks up the interpreter of the shebang in `text`, the alternatives to switch to, and the
+/// permission of the script.
+pub async fn lookup(text: &str, path: PathBuf) -> Lookup {
+    let mut interpreters = vec!["rust-script".to_owned(), "cargo".to_owned()];
+    interpreters.extend(first_line(text).and_then(interpreter));
+    tokio::task::spawn_blocking(move || Lookup {
+        not_found: interpreters
+            .into_iter()
+            .filter(|interpreter| find_executable(interpreter).is_none())
+            .collect(),
+        not_executable: !is_executable(&path),
+    })
+    .await
+    .unwrap_or_else(|e| {
+        tracing::warn!(?e, "failed to look up the interpreter");
+        Lookup::default()
+    })
+}
+
+/// Checks the shebang of the script and the permission of the file.
+pub fn diagnostics(document: &Document, encoding: Encoding, lookup: &Lookup) -> Vec<Diagnostic> {
+    check(document, lookup)
+        .into_iter()
+        .map(|problem| diagnostic(document, encoding, &problem))
+        .collect()
+}
+
+/// Creates quick fixes for the problems of the shebang, if `range` touches it.
+pub fn fixes(
+    uri: &Url,
+    document: &Document,
+    encoding: Encoding,
+    lookup: &Lookup,
+    range: Range,
+) -> Vec<CodeAction> {
+    if range.start.line > 0 {
+        return vec![];
+    }
+    let line_ending = document.line_ending();
+    let first_line = shebang_range(document, encoding);
+    let mut actions = vec![];
+    for problem in check(document, lookup) {
+        let diagnostic = diagnostic(document, encoding, &problem);
+        let mut replace = |title: String, new_text: String| {
+            actions.push(CodeAction {
+                title,
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        uri.clone(),
+                        vec![TextEdit::new(first_line, new_text)],
+                    )])),
+                    ..Default::default()
+                }),
+                is_preferred: Some(true),
+                ..Default::default()
+            })
+        };
+        match &problem {
+            Problem::Missing => replace(
+                format!("Insert `{RUST_SCRIPT_SHEBANG}`"),
+                format!("{RUST_SCRIPT_SHEBANG}{line_ending}"),
+            ),
+            Problem::Malformed => replace(
+                format!("Replace with `{RUST_SCRIPT_SHEBANG}`"),
+                RUST_SCRIPT_SHEBANG.to_owned(),
+            ),
+            Problem::NotFound(interpreter) => {
+                let alternative = if interpreter == "cargo" {
+                    ("rust-script", RUST_SCRIPT_SHEBANG)
+                } else {
+                    ("cargo", CARGO_SHEBANG)
+                };
+                if !lookup.not_found.contains(alternative.0) {
+                    replace(
+                        format!("Switch the interpreter to `{}`", alternative.0),
+                        alternative.1.to_owned(),
+                    );
+                }
+            }
+            Problem::NotExecutable => actions.push(CodeAction {
+                title: "Make the script executable".to_owned(),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic]),
+                command: Some(Command::new(
+                    "chmod +x".to_owned(),
+                    commands::MAKE_EXECUTABLE.to_owned(),
+                    Some(vec![json!(uri)]),
+                )),
+                is_preferred: Some(true),
+                ..Default::default()
+            }),
+        }
+    }
+    actions
+}
+
+fn check(document: &Document, lookup: &Lookup) -> Vec<Problem> {
+    let Some(line) = document.shebang() else {
+        return vec![Problem::Missing];
+    };
+    let mut problems = vec![];
+    match interpreter(line) {
+        Some(interpreter) => {
+            if lookup.not_found.contains(&interpreter) {
+                problems.push(Problem::NotFound(interpreter));
+            }
+        }
+        None => problems.push(Problem::Malformed),
+    }
+    if lookup.not_executable {
+        problems.push(Problem::NotExecutable);
+    }
+    problems
+}
+
+/// Returns the first line of the text if it's a shebang, i.e. starts with `#!` but not `#![`, which
+/// is an inner attribute.
+pub fn first_line(text: &str) -> Option<&str> {
+    text.lines()
+        .next()
+        .filter(|line| line.starts_with("#!") && !line.starts_with("#!["))
+}
+
+/// Returns the interpreter of the shebang if it's rust-script or cargo, looking through `env`.
+pub fn interpreter(shebang: &str) -> Option<String> {
+    let mut words = shebang.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?;
+    if file_name(program) == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    matches!(file_name(program), "rust-script" | "cargo").then(|| program.to_owned())
+}
+
+fn file_name(program: &str) -> &str {
+    program.rsplit('/').next().unwrap_or(program)
+}
+
+/// Finds the program as a shell does, i.e. either a path or a name in `PATH`.
+fn find_executable(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn shebang_range(document: &Document, encoding: Encoding) -> Range {
+    match document.shebang() {
+        Some(line) => Range::new(
+            Position::new(0, 0),
+            Position::new(0, encoding.str_len(line)),
+        ),
+        None => Range::new(Position::new(0, 0), Position::new(0, 0)),
+    }
+}
+
+fn diagnostic(document: &Document, encoding: Encoding, problem: &Problem) -> Diagnostic {
+    Diagnostic {
+        range: shebang_range(document, encoding),
+        severity: Some(problem.severity()),
+        code: Some(NumberOrString::String(problem.code().to_owned())),
+        source: Some(SOURCE.to_owned()),
+        message: problem.message(),
+        ..Default::default()
+    }
+}